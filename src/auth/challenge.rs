@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand_core::{OsRng, RngCore};
+use redis::{AsyncCommands, Client};
+
+use crate::auth::model::SessionMetadata;
+
+pub const CHALLENGE_TTL_SECS: i64 = 5 * 60;
+const MAX_ATTEMPTS: i64 = 5;
+
+fn challenge_key(token: &str) -> String {
+    format!("login_challenge:{token}")
+}
+
+/// A password-verified login waiting for its second factor.
+pub struct LoginChallenge {
+    pub user_id: String,
+    pub device_id: String,
+    pub metadata: SessionMetadata,
+}
+
+pub async fn create_challenge(
+    redis: &Client,
+    user_id: &str,
+    device_id: &str,
+    metadata: &SessionMetadata,
+) -> redis::RedisResult<String> {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+
+    let mut fields = vec![
+        ("user_id", user_id.to_string()),
+        ("device_id", device_id.to_string()),
+        ("attempts", "0".to_string()),
+    ];
+    if let Some(device_name) = &metadata.device_name {
+        fields.push(("device_name", device_name.clone()));
+    }
+    if let Some(user_agent) = &metadata.user_agent {
+        fields.push(("user_agent", user_agent.clone()));
+    }
+    if let Some(ip) = &metadata.ip {
+        fields.push(("ip", ip.clone()));
+    }
+
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    let key = challenge_key(&token);
+    redis::pipe()
+        .atomic()
+        .hset_multiple(&key, &fields)
+        .ignore()
+        .expire(&key, CHALLENGE_TTL_SECS)
+        .ignore()
+        .query_async::<()>(&mut conn)
+        .await?;
+    Ok(token)
+}
+
+pub async fn get_challenge(
+    redis: &Client,
+    token: &str,
+) -> redis::RedisResult<Option<LoginChallenge>> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    let mut fields: HashMap<String, String> = conn.hgetall(challenge_key(token)).await?;
+
+    let (Some(user_id), Some(device_id)) = (fields.remove("user_id"), fields.remove("device_id"))
+    else {
+        return Ok(None);
+    };
+    Ok(Some(LoginChallenge {
+        user_id,
+        device_id,
+        metadata: SessionMetadata {
+            device_name: fields.remove("device_name"),
+            user_agent: fields.remove("user_agent"),
+            ip: fields.remove("ip"),
+        },
+    }))
+}
+
+/// Counts a wrong second factor; the challenge is dropped once it has been
+/// guessed at too many times.
+pub async fn record_failed_attempt(redis: &Client, token: &str) -> redis::RedisResult<()> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    let script = redis::Script::new(
+        r"
+        if redis.call('EXISTS', KEYS[1]) == 0 then
+            return 0
+        end
+        if redis.call('HINCRBY', KEYS[1], 'attempts', 1) >= tonumber(ARGV[1]) then
+            redis.call('DEL', KEYS[1])
+        end
+        return 0
+        ",
+    );
+    let _: i64 = script
+        .key(challenge_key(token))
+        .arg(MAX_ATTEMPTS)
+        .invoke_async(&mut conn)
+        .await?;
+    Ok(())
+}
+
+/// Consumes the challenge. Returns `false` if another request already did.
+pub async fn consume_challenge(redis: &Client, token: &str) -> redis::RedisResult<bool> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    let deleted: i64 = conn.del(challenge_key(token)).await?;
+    Ok(deleted == 1)
+}
@@ -1,34 +1,66 @@
-use crate::auth::whitelist::is_jti_valid;
+use crate::auth::utils::handle_refresh_reuse;
+use crate::auth::whitelist::{is_jti_valid, is_refresh_jti_rotated, touch_session};
 use crate::state::AppState;
 use crate::utils::error::error_response;
 use axum::extract::State;
 use axum::http::StatusCode;
 use axum::Json;
 use axum::{http::Request, middleware::Next, response::Response};
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
-use shuttle_runtime::SecretStore;
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::auth::keyring::KeyRing;
+
+pub const ACCESS_TOKEN_TTL_SECS: i64 = 15 * 60;
+pub const REFRESH_TOKEN_TTL_SECS: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
+    pub device_id: String,
+    pub family: String,
     pub jti: String,
     pub exp: usize,
     pub token_type: String,
 }
 
-pub fn create_access_token(user_id: &str, secret_store: &SecretStore) -> String {
-    create_jwt(user_id, secret_store, 15 * 60, "access")
+pub fn create_access_token(
+    user_id: &str,
+    device_id: &str,
+    family: &str,
+    keyring: &KeyRing,
+) -> String {
+    create_jwt(
+        user_id,
+        device_id,
+        family,
+        keyring,
+        ACCESS_TOKEN_TTL_SECS,
+        "access",
+    )
 }
 
-pub fn create_refresh_token(user_id: &str, secret_store: &SecretStore) -> String {
-    create_jwt(user_id, secret_store, 24 * 60 * 60, "refresh")
+pub fn create_refresh_token(
+    user_id: &str,
+    device_id: &str,
+    family: &str,
+    keyring: &KeyRing,
+) -> String {
+    create_jwt(
+        user_id,
+        device_id,
+        family,
+        keyring,
+        REFRESH_TOKEN_TTL_SECS,
+        "refresh",
+    )
 }
 
 fn create_jwt(
     user_id: &str,
-    secret_store: &SecretStore,
+    device_id: &str,
+    family: &str,
+    keyring: &KeyRing,
     expiration_secs: i64,
     token_type: &str,
 ) -> String {
@@ -39,48 +71,27 @@ fn create_jwt(
 
     let claims = Claims {
         sub: user_id.to_owned(),
+        device_id: device_id.to_owned(),
+        family: family.to_owned(),
         jti: Uuid::new_v4().to_string(),
         exp: expiration,
         token_type: token_type.to_string(),
     };
 
-    let secret = secret_store.get("JWT_SECRET").expect("missing jwt_secret");
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.as_ref()),
-    )
-    .expect("JWT creation failed")
+    keyring.sign(&claims).expect("JWT creation failed")
 }
 
-pub fn decode_jwt(
-    token: &str,
-    secret_store: &SecretStore,
-) -> Result<Claims, jsonwebtoken::errors::Error> {
-    let secret = secret_store
-        .get("JWT_SECRET")
-        .expect("JWT_SECRET not found in Secrets.toml");
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    )?;
-    Ok(token_data.claims)
+pub fn decode_jwt(token: &str, keyring: &KeyRing) -> Result<Claims, jsonwebtoken::errors::Error> {
+    keyring.verify(token)
 }
 
-pub async fn require_access_token(
-    State(state): State<AppState>,
-    mut req: Request<axum::body::Body>,
-    next: Next,
-) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
-    let token = req
-        .headers()
-        .get("Authorization")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or_else(|| error_response(StatusCode::UNAUTHORIZED, Some("Missing token")))?;
-
-    let claims = decode_jwt(token, &state.secret_store)
+/// Checks an access token the way every protected route does: signature,
+/// token type, whitelisted jti. Also bumps the session's `last_used_at`.
+pub async fn validate_access_token(
+    state: &AppState,
+    token: &str,
+) -> Result<Claims, (StatusCode, Json<serde_json::Value>)> {
+    let claims = decode_jwt(token, &state.keyring)
         .map_err(|_| error_response(StatusCode::UNAUTHORIZED, None))?;
 
     if claims.token_type != "access" {
@@ -90,9 +101,15 @@ pub async fn require_access_token(
         ));
     }
 
-    if !is_jti_valid(&state.redis, &claims.sub, &claims.jti, "access")
-        .await
-        .unwrap_or(false)
+    if !is_jti_valid(
+        &state.redis,
+        &claims.sub,
+        &claims.device_id,
+        &claims.jti,
+        "access",
+    )
+    .await
+    .unwrap_or(false)
     {
         return Err(error_response(
             StatusCode::UNAUTHORIZED,
@@ -100,7 +117,26 @@ pub async fn require_access_token(
         ));
     }
 
+    let _ = touch_session(&state.redis, &claims.sub, &claims.device_id).await;
+    Ok(claims)
+}
+
+pub async fn require_access_token(
+    State(state): State<AppState>,
+    mut req: Request<axum::body::Body>,
+    next: Next,
+) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
+    let token = req
+        .headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .ok_or_else(|| error_response(StatusCode::UNAUTHORIZED, Some("Missing token")))?;
+
+    let claims = validate_access_token(&state, token).await?;
+
     req.extensions_mut().insert(claims.sub.clone());
+    req.extensions_mut().insert(claims);
     Ok(next.run(req).await)
 }
 
@@ -116,13 +152,25 @@ pub async fn require_refresh_token(
         .and_then(|s| s.strip_prefix("Bearer "))
         .ok_or_else(|| error_response(StatusCode::UNAUTHORIZED, Some("Missing token")))?;
 
-    let claims = decode_jwt(token, &state.secret_store)
+    let claims = decode_jwt(token, &state.keyring)
         .map_err(|_| error_response(StatusCode::UNAUTHORIZED, None))?;
 
-    if !is_jti_valid(&state.redis, &claims.sub, &claims.jti, "refresh")
-        .await
-        .unwrap_or(false)
+    if !is_jti_valid(
+        &state.redis,
+        &claims.sub,
+        &claims.device_id,
+        &claims.jti,
+        "refresh",
+    )
+    .await
+    .unwrap_or(false)
     {
+        if is_refresh_jti_rotated(&state.redis, &claims.family, &claims.jti)
+            .await
+            .unwrap_or(false)
+        {
+            return Err(handle_refresh_reuse(&state, &claims).await);
+        }
         return Err(error_response(
             StatusCode::UNAUTHORIZED,
             Some("Invalid token"),
@@ -136,6 +184,9 @@ pub async fn require_refresh_token(
         ));
     }
 
+    let _ = touch_session(&state.redis, &claims.sub, &claims.device_id).await;
+
     req.extensions_mut().insert(claims.sub.clone());
+    req.extensions_mut().insert(claims);
     Ok(next.run(req).await)
 }
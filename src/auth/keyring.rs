@@ -0,0 +1,149 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode, decode_header, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header,
+    Validation,
+};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use shuttle_runtime::SecretStore;
+
+#[derive(Clone)]
+struct VerificationKey {
+    kid: String,
+    x: String,
+    decoding_key: DecodingKey,
+}
+
+impl VerificationKey {
+    fn from_public_key(public_key: &[u8]) -> Self {
+        let x = URL_SAFE_NO_PAD.encode(public_key);
+        Self {
+            kid: thumbprint(&x),
+            decoding_key: DecodingKey::from_ed_der(public_key),
+            x,
+        }
+    }
+}
+
+/// Ed25519 keys used to sign and verify our JWTs.
+///
+/// Tokens are signed with the current key only; retired keys listed in
+/// `JWT_PREVIOUS_PUBLIC_KEYS` stay valid for verification so a rotation does
+/// not log everyone out.
+#[derive(Clone)]
+pub struct KeyRing {
+    signing_kid: String,
+    signing_key: Arc<Ed25519KeyPair>,
+    encoding_key: EncodingKey,
+    verification_keys: Vec<VerificationKey>,
+}
+
+impl KeyRing {
+    pub fn from_secret_store(secret_store: &SecretStore) -> anyhow::Result<Self> {
+        let signing_pem = secret_store
+            .get("JWT_SIGNING_KEY")
+            .context("missing JWT_SIGNING_KEY")?;
+        let previous = secret_store
+            .get("JWT_PREVIOUS_PUBLIC_KEYS")
+            .unwrap_or_default();
+        Self::new(&signing_pem, &previous)
+    }
+
+    /// `signing_pem` is a PKCS#8 Ed25519 private key, `previous_public_keys` a
+    /// comma separated list of base64url raw public keys.
+    pub fn new(signing_pem: &str, previous_public_keys: &str) -> anyhow::Result<Self> {
+        let pkcs8 = pem::parse(signing_pem)
+            .context("JWT_SIGNING_KEY is not valid PEM")?
+            .into_contents();
+        let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pkcs8)
+            .map_err(|_| anyhow!("JWT_SIGNING_KEY is not an Ed25519 PKCS#8 key"))?;
+        let current = VerificationKey::from_public_key(key_pair.public_key().as_ref());
+
+        let mut verification_keys = vec![current.clone()];
+        for encoded in previous_public_keys
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+        {
+            let public_key = URL_SAFE_NO_PAD
+                .decode(encoded)
+                .context("invalid key in JWT_PREVIOUS_PUBLIC_KEYS")?;
+            if public_key.len() != 32 {
+                return Err(anyhow!("JWT_PREVIOUS_PUBLIC_KEYS entries must be 32 bytes"));
+            }
+            let key = VerificationKey::from_public_key(&public_key);
+            if key.kid != current.kid {
+                verification_keys.push(key);
+            }
+        }
+
+        Ok(Self {
+            signing_kid: current.kid,
+            signing_key: Arc::new(key_pair),
+            encoding_key: EncodingKey::from_ed_der(&pkcs8),
+            verification_keys,
+        })
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, jsonwebtoken::errors::Error> {
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(self.signing_kid.clone());
+        encode(&header, claims, &self.encoding_key)
+    }
+
+    /// Raw Ed25519 signature with the current signing key, for payloads that
+    /// are not JWTs (e.g. transparency log tree heads).
+    pub fn sign_bytes(&self, message: &[u8]) -> Vec<u8> {
+        self.signing_key.sign(message).as_ref().to_vec()
+    }
+
+    pub fn signing_kid(&self) -> &str {
+        &self.signing_kid
+    }
+
+    pub fn verify<T: DeserializeOwned>(
+        &self,
+        token: &str,
+    ) -> Result<T, jsonwebtoken::errors::Error> {
+        let header = decode_header(token)?;
+        let kid = header.kid.ok_or(ErrorKind::InvalidToken)?;
+        let key = self
+            .verification_keys
+            .iter()
+            .find(|key| key.kid == kid)
+            .ok_or(ErrorKind::InvalidSignature)?;
+
+        let token_data = decode::<T>(token, &key.decoding_key, &Validation::new(Algorithm::EdDSA))?;
+        Ok(token_data.claims)
+    }
+
+    /// Public half of every verification key as a JWK Set (RFC 7517).
+    pub fn jwks(&self) -> Value {
+        let keys: Vec<Value> = self
+            .verification_keys
+            .iter()
+            .map(|key| {
+                json!({
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "alg": "EdDSA",
+                    "use": "sig",
+                    "kid": key.kid,
+                    "x": key.x,
+                })
+            })
+            .collect();
+        json!({ "keys": keys })
+    }
+}
+
+/// RFC 7638 JWK thumbprint, used as the `kid`.
+fn thumbprint(x: &str) -> String {
+    let canonical = format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{x}"}}"#);
+    URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes()))
+}
@@ -1,6 +1,10 @@
+pub mod challenge;
 pub mod jwt;
+pub mod keyring;
 pub mod model;
 pub mod password;
 pub mod services;
+pub mod throttle;
+pub mod totp;
 pub mod utils;
 pub mod whitelist;
@@ -1,18 +1,113 @@
+use axum::http::HeaderMap;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use crate::user::models::OneTimePreKeyPublic;
+use crate::{
+    user::models::OneTimePreKeyPublic,
+    utils::request::{forwarded_client_ip, truncate, user_agent, MAX_DEVICE_NAME_LEN},
+};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct LoginPayload {
     pub username: String,
     pub password: String,
+    pub device_id: Option<String>,
+    pub device_name: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct RegisterPayload {
     pub username: String,
     pub password: String,
+    pub device_id: Option<String>,
+    pub device_name: Option<String>,
     pub ik_pub: [u8; 32],
     pub spk_pub: [u8; 32],
+    pub spk_signature: Vec<u8>,
     pub opk_pub: Vec<OneTimePreKeyPublic>,
 }
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChangePasswordPayload {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TotpCodePayload {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DisableTotpPayload {
+    pub password: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TwoFactorLoginPayload {
+    pub challenge: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SessionMetadata {
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    /// Client reported address, shown in the session list only.
+    pub ip: Option<String>,
+}
+
+impl SessionMetadata {
+    pub fn from_headers(headers: &HeaderMap, device_name: Option<String>) -> Self {
+        Self {
+            device_name: device_name.map(|name| truncate(&name, MAX_DEVICE_NAME_LEN)),
+            user_agent: user_agent(headers),
+            ip: forwarded_client_ip(headers),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+    pub current: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityEventKind {
+    RefreshTokenReuse,
+    PasswordChanged,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    RecoveryCodeUsed,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SecurityEvent {
+    pub uuid: String,
+    pub user_id: String,
+    pub kind: SecurityEventKind,
+    pub device_id: Option<String>,
+    pub created_at: i64,
+}
+
+impl SecurityEvent {
+    pub fn new(user_id: &str, kind: SecurityEventKind, device_id: Option<&str>) -> Self {
+        Self {
+            uuid: Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            kind,
+            device_id: device_id.map(str::to_string),
+            created_at: chrono::Utc::now().timestamp(),
+        }
+    }
+}
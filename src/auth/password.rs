@@ -1,12 +1,130 @@
-use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::Context;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
 use axum::{http::StatusCode, Json};
 use password_hash::SaltString;
 use rand_core::OsRng;
 use serde_json::{json, Value};
+use shuttle_runtime::SecretStore;
+
+/// Passwords so common that they are worth next to nothing when found inside
+/// a longer one.
+const COMMON_PATTERNS: &[&str] = &[
+    "password", "qwerty", "azerty", "letmein", "welcome", "admin", "iloveyou", "monkey", "dragon",
+    "football", "baseball", "sunshine", "princess", "master", "login", "abc123", "123456",
+    "lucchat",
+];
+const KEYBOARD_ROWS: &[&str] = &[
+    "qwertyuiop",
+    "asdfghjkl",
+    "zxcvbnm",
+    "azertyuiop",
+    "1234567890",
+];
+
+/// Password rules and hashing cost, read from the `SecretStore` at startup.
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_special: bool,
+    pub min_entropy_bits: f64,
+    pub breached_passwords: Arc<HashSet<String>>,
+    pub argon2_params: Params,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 12,
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_special: true,
+            min_entropy_bits: 40.0,
+            breached_passwords: Arc::new(HashSet::new()),
+            argon2_params: Params::default(),
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn from_secret_store(secret_store: &SecretStore) -> anyhow::Result<Self> {
+        let defaults = Self::default();
+        let get = |key: &str| secret_store.get(key).filter(|v| !v.trim().is_empty());
+        let parse_bool = |key: &str, default: bool| -> anyhow::Result<bool> {
+            get(key).map_or(Ok(default), |v| {
+                v.trim().parse().with_context(|| format!("invalid {key}"))
+            })
+        };
+        let parse_u32 = |key: &str, default: u32| -> anyhow::Result<u32> {
+            get(key).map_or(Ok(default), |v| {
+                v.trim().parse().with_context(|| format!("invalid {key}"))
+            })
+        };
+
+        let breached_passwords = match get("PASSWORD_BREACHED_LIST") {
+            Some(path) => std::fs::read_to_string(path.trim())
+                .with_context(|| format!("cannot read breached password list {path}"))?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect(),
+            None => HashSet::new(),
+        };
+
+        let argon2_params = Params::new(
+            parse_u32("ARGON2_MEMORY_KIB", defaults.argon2_params.m_cost())?,
+            parse_u32("ARGON2_ITERATIONS", defaults.argon2_params.t_cost())?,
+            parse_u32("ARGON2_PARALLELISM", defaults.argon2_params.p_cost())?,
+            None,
+        )
+        .map_err(|e| anyhow::anyhow!("invalid argon2 parameters: {e}"))?;
+
+        Ok(Self {
+            min_length: parse_u32("PASSWORD_MIN_LENGTH", defaults.min_length as u32)? as usize,
+            require_lowercase: parse_bool(
+                "PASSWORD_REQUIRE_LOWERCASE",
+                defaults.require_lowercase,
+            )?,
+            require_uppercase: parse_bool(
+                "PASSWORD_REQUIRE_UPPERCASE",
+                defaults.require_uppercase,
+            )?,
+            require_digit: parse_bool("PASSWORD_REQUIRE_DIGIT", defaults.require_digit)?,
+            require_special: parse_bool("PASSWORD_REQUIRE_SPECIAL", defaults.require_special)?,
+            min_entropy_bits: get("PASSWORD_MIN_ENTROPY_BITS").map_or(
+                Ok(defaults.min_entropy_bits),
+                |v| {
+                    v.trim()
+                        .parse()
+                        .context("invalid PASSWORD_MIN_ENTROPY_BITS")
+                },
+            )?,
+            breached_passwords: Arc::new(breached_passwords),
+            argon2_params,
+        })
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            self.argon2_params.clone(),
+        )
+    }
+}
 
-pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+pub fn hash_password(
+    password: &str,
+    policy: &PasswordPolicy,
+) -> Result<String, argon2::password_hash::Error> {
     let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
+    let argon2 = policy.argon2();
 
     let hash = argon2
         .hash_password(password.as_bytes(), &salt)?
@@ -24,16 +142,126 @@ pub fn verify_password(password: &str, hash: &str) -> Result<bool, argon2::passw
         .is_ok())
 }
 
-pub fn is_password_strong(password: &str) -> Result<bool, (StatusCode, Json<Value>)> {
-    check_min_length(password, 12)?;
-    check_lowercase(password)?;
-    check_uppercase(password)?;
-    check_digit(password)?;
-    check_special_char(password)?;
+/// Whether a stored hash was produced with a different algorithm or cost than
+/// the one currently configured.
+pub fn needs_rehash(hash: &str, policy: &PasswordPolicy) -> bool {
+    let Ok(parsed_hash) = PasswordHash::new(hash) else {
+        return true;
+    };
+    if parsed_hash.algorithm != Algorithm::Argon2id.ident() {
+        return true;
+    }
+    let Ok(params) = Params::try_from(&parsed_hash) else {
+        return true;
+    };
+    let current = &policy.argon2_params;
+    params.m_cost() != current.m_cost()
+        || params.t_cost() != current.t_cost()
+        || params.p_cost() != current.p_cost()
+}
+
+pub fn is_password_strong(
+    password: &str,
+    policy: &PasswordPolicy,
+) -> Result<bool, (StatusCode, Json<Value>)> {
+    check_min_length(password, policy.min_length)?;
+    if policy.require_lowercase {
+        check_lowercase(password)?;
+    }
+    if policy.require_uppercase {
+        check_uppercase(password)?;
+    }
+    if policy.require_digit {
+        check_digit(password)?;
+    }
+    if policy.require_special {
+        check_special_char(password)?;
+    }
+    check_entropy(password, policy.min_entropy_bits)?;
+    check_breached(password, &policy.breached_passwords)?;
 
     Ok(true)
 }
 
+/// Rough guessability estimate in the spirit of zxcvbn: every character is
+/// worth log2 of the alphabet it draws from, except characters that repeat the
+/// previous one, continue a sequence or keyboard row, or belong to a very
+/// common password, which are worth almost nothing.
+pub fn estimate_entropy_bits(password: &str) -> f64 {
+    let chars: Vec<char> = password.chars().collect();
+    if chars.is_empty() {
+        return 0.0;
+    }
+
+    let mut pool = 0u32;
+    if chars.iter().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if chars.iter().any(|c| !c.is_ascii_alphanumeric()) {
+        pool += 33;
+    }
+    let bits_per_char = f64::from(pool.max(1)).log2();
+
+    let lowered: Vec<char> = chars.iter().map(|c| c.to_ascii_lowercase()).collect();
+    let mut weak = vec![false; chars.len()];
+
+    let lowered_text: String = lowered.iter().collect();
+    let unleeted_text: String = lowered.iter().map(|c| unleet(*c)).collect();
+    for text in [&lowered_text, &unleeted_text] {
+        for pattern in COMMON_PATTERNS {
+            for (start, _) in text.match_indices(pattern) {
+                let start = text[..start].chars().count();
+                weak[start..start + pattern.len()].fill(true);
+            }
+        }
+    }
+
+    let mut bits = 0.0;
+    let mut dictionary_hits = 0;
+    for i in 0..chars.len() {
+        if weak[i] {
+            if i == 0 || !weak[i - 1] {
+                dictionary_hits += 1;
+            }
+            continue;
+        }
+        let predictable = i > 0 && {
+            let (prev, cur) = (lowered[i - 1], lowered[i]);
+            let step = cur as i32 - prev as i32;
+            step == 0 || step.abs() == 1 || is_keyboard_neighbour(prev, cur)
+        };
+        bits += if predictable { 1.0 } else { bits_per_char };
+    }
+
+    bits + f64::from(dictionary_hits) * (COMMON_PATTERNS.len() as f64).log2()
+}
+
+fn unleet(c: char) -> char {
+    match c {
+        '@' | '4' => 'a',
+        '3' => 'e',
+        '1' | '!' => 'i',
+        '0' => 'o',
+        '$' | '5' => 's',
+        '7' => 't',
+        _ => c,
+    }
+}
+
+fn is_keyboard_neighbour(prev: char, cur: char) -> bool {
+    KEYBOARD_ROWS.iter().any(|row| {
+        row.find(prev)
+            .zip(row.find(cur))
+            .is_some_and(|(a, b)| a.abs_diff(b) == 1)
+    })
+}
+
 fn check_min_length(password: &str, min_length: usize) -> Result<bool, (StatusCode, Json<Value>)> {
     if password.len() >= min_length {
         return Ok(true);
@@ -92,3 +320,28 @@ fn check_special_char(password: &str) -> Result<bool, (StatusCode, Json<Value>)>
         ),
     ))
 }
+
+fn check_entropy(password: &str, min_entropy_bits: f64) -> Result<bool, (StatusCode, Json<Value>)> {
+    if estimate_entropy_bits(password) >= min_entropy_bits {
+        return Ok(true);
+    }
+    Err((
+        StatusCode::BAD_REQUEST,
+        Json(json!({ "error": {"code": 400, "message": "Password is too easy to guess" }})),
+    ))
+}
+
+fn check_breached(
+    password: &str,
+    breached_passwords: &HashSet<String>,
+) -> Result<bool, (StatusCode, Json<Value>)> {
+    if !breached_passwords.contains(password) {
+        return Ok(true);
+    }
+    Err((
+        StatusCode::BAD_REQUEST,
+        Json(
+            json!({ "error": {"code": 400, "message": "Password appears in a list of breached passwords" }}),
+        ),
+    ))
+}
@@ -1,80 +1,121 @@
+use crate::auth::challenge::{
+    consume_challenge, create_challenge, get_challenge, record_failed_attempt, CHALLENGE_TTL_SECS,
+};
+use crate::auth::keyring::KeyRing;
+use crate::auth::model::{
+    ChangePasswordPayload, DisableTotpPayload, LoginPayload, RegisterPayload, SecurityEvent,
+    SecurityEventKind, SessionInfo, SessionMetadata, TwoFactorLoginPayload,
+};
 use crate::auth::password::is_password_strong;
-use crate::auth::utils::update_jwt;
+use crate::auth::throttle::{login_lockout, record_login_failure, reset_login_failures};
+use crate::auth::totp::{generate_recovery_codes, generate_secret, otpauth_uri};
+use crate::auth::utils::{
+    handle_refresh_reuse, record_security_event, resolve_device_id, start_session, update_jwt,
+    verify_second_factor, SecondFactor,
+};
+use crate::auth::whitelist::{
+    get_sessions, revoke_all_sessions, revoke_other_sessions, revoke_session, rotate_refresh_jti,
+    session_exists,
+};
+use crate::keys::utils::{validate_opks, verify_signed_prekey};
 use crate::state::AppState;
-use crate::user::models::{OneTimePreKeyPublic, User, UserPrivate};
-use crate::utils::error::error_response;
+use crate::transparency::{self, models::LogEntry};
+use crate::user::models::{TotpConfig, User};
+use crate::user::utils::{find_user, update_user_fields};
+use crate::utils::error::{error_response, session_store_error, too_many_requests};
+use crate::utils::request::ClientAddr;
 use crate::{
     auth::{
         jwt::decode_jwt,
-        password::{hash_password, verify_password},
+        password::{hash_password, needs_rehash, verify_password, PasswordPolicy},
     },
     user::models::Key,
 };
 use axum::{
     http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
-use mongodb::bson::doc;
+use mongodb::bson::{doc, to_bson};
 use mongodb::Collection;
 use serde_json::{json, Value};
-use shuttle_runtime::SecretStore;
 
 pub async fn login(
     users: Collection<User>,
-    secret_store: SecretStore,
+    keyring: &KeyRing,
     redis_client: redis::Client,
-    username: String,
-    password: String,
+    password_policy: &PasswordPolicy,
+    payload: LoginPayload,
+    metadata: SessionMetadata,
+    client_addr: Option<ClientAddr>,
 ) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let LoginPayload {
+        username,
+        password,
+        device_id,
+        ..
+    } = payload;
+    let device_id = resolve_device_id(device_id)?;
     let user = users
-        .find_one(doc! { "username": username })
+        .find_one(doc! { "username": &username })
         .await
-        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?
-        .ok_or(error_response(
-            StatusCode::UNAUTHORIZED,
-            Some("Invalid credentials"),
-        ))?;
-
-    let is_valid = verify_password(&password, &user.password_hash).unwrap_or(false);
-
-    if is_valid {
-        let (access_token, refresh_token) =
-            update_jwt(&user.uuid, &secret_store, &redis_client).await?;
-
-        let user_private = UserPrivate {
-            uuid: user.uuid,
-            username: user.username,
-            keys: user.keys,
-            description: user.description,
-            profile_picture: user.profile_picture,
-            pending_friend_requests: user.pending_friend_requests,
-            friends_requests: user.friends_requests,
-            friends: user.friends,
-        };
-        Ok(Json(json!({ 
-            "user": user_private, 
-            "token": {
-                "access": access_token,
-                "refresh": refresh_token
-            } })))
-    } else {
-        Err(error_response(
-            StatusCode::UNAUTHORIZED,
-            Some("Invalid credentials"),
-        ))
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
+
+    let user = match user {
+        Some(user) if verify_password(&password, &user.password_hash).unwrap_or(false) => user,
+        _ => {
+            let ip = client_addr.map(|ClientAddr(ip)| ip);
+            let _ = record_login_failure(&redis_client, &username, ip).await;
+            return Err(error_response(
+                StatusCode::UNAUTHORIZED,
+                Some("Invalid credentials"),
+            ));
+        }
+    };
+    if needs_rehash(&user.password_hash, password_policy) {
+        if let Ok(hashed) = hash_password(&password, password_policy) {
+            let _ = update_user_fields(&users, &user.uuid, doc! { "password_hash": hashed }).await;
+        }
+    }
+
+    if user.totp.as_ref().is_some_and(|totp| totp.enabled) {
+        let challenge = create_challenge(&redis_client, &user.uuid, &device_id, &metadata)
+            .await
+            .map_err(session_store_error)?;
+        return Ok(Json(json!({
+            "two_factor_required": true,
+            "challenge": challenge,
+            "expires_in": CHALLENGE_TTL_SECS,
+        })));
     }
+
+    // With two-factor on, the counters are only cleared once the second
+    // factor passes too.
+    let _ = reset_login_failures(&redis_client, &username).await;
+    start_session(user, &device_id, &metadata, keyring, &redis_client).await
 }
 
 pub async fn register(
     users: Collection<User>,
-    secret_store: SecretStore,
+    transparency_log: &Collection<LogEntry>,
+    keyring: &KeyRing,
     redis_client: redis::Client,
-    username: String,
-    password: String,
-    ik_pub: [u8; 32],
-    spk_pub: [u8; 32],
-    opk_pub: Vec<OneTimePreKeyPublic>,
+    password_policy: &PasswordPolicy,
+    payload: RegisterPayload,
+    metadata: SessionMetadata,
 ) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let RegisterPayload {
+        username,
+        password,
+        device_id,
+        ik_pub,
+        spk_pub,
+        spk_signature,
+        opk_pub,
+        ..
+    } = payload;
+    let device_id = resolve_device_id(device_id)?;
+
     let existing_user = users
         .find_one(doc! { "username": username.clone() })
         .await
@@ -87,38 +128,38 @@ pub async fn register(
         ));
     }
 
-    is_password_strong(&password)?;
+    is_password_strong(&password, password_policy)?;
+    verify_signed_prekey(&ik_pub, &spk_pub, &spk_signature)?;
+    validate_opks(&opk_pub)?;
 
-    let hashed = hash_password(&password)
+    let hashed = hash_password(&password, password_policy)
         .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, None))?;
-    let keys = Key::new(ik_pub, spk_pub, opk_pub);
+    let keys = Key::new(ik_pub, spk_pub, spk_signature, opk_pub);
     let user = User::new(username, hashed, keys);
 
+    // Published before the account exists, so no identity key is ever served
+    // without its binding in the log.
+    transparency::services::append(
+        transparency_log,
+        &user.uuid,
+        None,
+        user.keys.ik_pub,
+        user.ik_version,
+    )
+    .await
+    .map_err(|_| {
+        error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Some("Transparency log error"),
+        )
+    })?;
+
     users
         .insert_one(&user)
         .await
         .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, None))?;
 
-    let (access_token, refresh_token) =
-        update_jwt(&user.uuid, &secret_store, &redis_client).await?;
-
-    let user_private = UserPrivate {
-        uuid: user.uuid,
-        username: user.username,
-        keys: user.keys,
-        description: user.description,
-        profile_picture: user.profile_picture,
-        pending_friend_requests: user.pending_friend_requests,
-        friends_requests: user.friends_requests,
-        friends: user.friends,
-    };
-    Ok(Json(json!({
-        "user": user_private,
-        "token": {
-            "access": access_token,
-            "refresh": refresh_token
-        }
-    })))
+    start_session(user, &device_id, &metadata, keyring, &redis_client).await
 }
 
 pub async fn refresh_token(
@@ -131,15 +172,28 @@ pub async fn refresh_token(
         .and_then(|s| s.strip_prefix("Bearer "))
         .ok_or(error_response(StatusCode::UNAUTHORIZED, None))?;
 
-    let claims = decode_jwt(old_token, &state.secret_store)
+    let claims = decode_jwt(old_token, &state.keyring)
         .map_err(|_| error_response(StatusCode::UNAUTHORIZED, None))?;
 
     if claims.token_type != "refresh" {
         return Err(error_response(StatusCode::UNAUTHORIZED, None));
     }
 
-    let (new_access, new_refresh) =
-        update_jwt(&claims.sub, &state.secret_store, &state.redis).await?;
+    let first_use = rotate_refresh_jti(&state.redis, &claims.family, &claims.jti)
+        .await
+        .map_err(session_store_error)?;
+    if !first_use {
+        return Err(handle_refresh_reuse(state, &claims).await);
+    }
+
+    let (new_access, new_refresh) = update_jwt(
+        &claims.sub,
+        &claims.device_id,
+        &claims.family,
+        &state.keyring,
+        &state.redis,
+    )
+    .await?;
     Ok(Json(json!({
         "token": {
             "access": new_access,
@@ -147,3 +201,346 @@ pub async fn refresh_token(
         }
     })))
 }
+
+pub async fn logout(
+    redis_client: &redis::Client,
+    user_id: &str,
+    device_id: &str,
+) -> Result<(), (StatusCode, Json<Value>)> {
+    revoke_session(redis_client, user_id, device_id)
+        .await
+        .map_err(session_store_error)
+}
+
+pub async fn logout_all(
+    redis_client: &redis::Client,
+    user_id: &str,
+) -> Result<(), (StatusCode, Json<Value>)> {
+    revoke_all_sessions(redis_client, user_id)
+        .await
+        .map_err(session_store_error)
+}
+
+pub async fn list_sessions(
+    redis_client: &redis::Client,
+    user_id: &str,
+    current_device_id: &str,
+) -> Result<Vec<SessionInfo>, (StatusCode, Json<Value>)> {
+    let sessions = get_sessions(redis_client, user_id)
+        .await
+        .map_err(session_store_error)?;
+
+    let mut sessions: Vec<SessionInfo> = sessions
+        .into_iter()
+        .map(|(id, mut fields)| SessionInfo {
+            current: id == current_device_id,
+            id,
+            device_name: fields.remove("device_name"),
+            user_agent: fields.remove("user_agent"),
+            ip: fields.remove("ip"),
+            created_at: fields.get("created_at").and_then(|v| v.parse().ok()),
+            last_used_at: fields.get("last_used_at").and_then(|v| v.parse().ok()),
+        })
+        .collect();
+    sessions.sort_by_key(|s| std::cmp::Reverse(s.last_used_at));
+    Ok(sessions)
+}
+
+pub async fn revoke_session_by_id(
+    redis_client: &redis::Client,
+    user_id: &str,
+    device_id: &str,
+) -> Result<(), (StatusCode, Json<Value>)> {
+    let exists = session_exists(redis_client, user_id, device_id)
+        .await
+        .map_err(session_store_error)?;
+    if !exists {
+        return Err(error_response(
+            StatusCode::NOT_FOUND,
+            Some("Session not found"),
+        ));
+    }
+
+    logout(redis_client, user_id, device_id).await
+}
+
+/// Checks the password of a signed-in user before a sensitive change. Wrong
+/// guesses count towards the login lockout of the username, so a stolen
+/// access token does not allow guessing it unthrottled.
+async fn verify_current_password(
+    state: &AppState,
+    user: &User,
+    password: &str,
+) -> Result<(), Response> {
+    let lockout = login_lockout(&state.redis, &user.username, None)
+        .await
+        .unwrap_or(None);
+    if let Some(retry_after) = lockout {
+        return Err(too_many_requests(retry_after));
+    }
+    if !verify_password(password, &user.password_hash).unwrap_or(false) {
+        let _ = record_login_failure(&state.redis, &user.username, None).await;
+        return Err(
+            error_response(StatusCode::UNAUTHORIZED, Some("Invalid credentials")).into_response(),
+        );
+    }
+    Ok(())
+}
+
+pub async fn change_password(
+    state: &AppState,
+    user_id: &str,
+    device_id: &str,
+    payload: ChangePasswordPayload,
+) -> Result<(), Response> {
+    let users = state.get_user_collection();
+    let user = find_user(&users, user_id)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    verify_current_password(state, &user, &payload.current_password).await?;
+
+    if payload.current_password == payload.new_password {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            Some("New password must differ from the current one"),
+        )
+        .into_response());
+    }
+
+    is_password_strong(&payload.new_password, &state.password_policy)
+        .map_err(IntoResponse::into_response)?;
+
+    let hashed = hash_password(&payload.new_password, &state.password_policy)
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, None).into_response())?;
+    update_user_fields(&users, user_id, doc! { "password_hash": hashed })
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    revoke_other_sessions(&state.redis, user_id, device_id)
+        .await
+        .map_err(|e| session_store_error(e).into_response())?;
+
+    record_security_event(
+        &state.get_security_event_collection(),
+        SecurityEvent::new(user_id, SecurityEventKind::PasswordChanged, Some(device_id)),
+    )
+    .await;
+
+    Ok(())
+}
+
+pub async fn setup_totp(
+    users: Collection<User>,
+    user_id: &str,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let user = find_user(&users, user_id).await?;
+    if user.totp.as_ref().is_some_and(|totp| totp.enabled) {
+        return Err(error_response(
+            StatusCode::CONFLICT,
+            Some("Two-factor authentication already enabled"),
+        ));
+    }
+
+    let secret = generate_secret();
+    let totp = TotpConfig {
+        secret: secret.clone(),
+        enabled: false,
+        recovery_codes: Vec::new(),
+        last_used_step: None,
+    };
+    let totp =
+        to_bson(&totp).map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, None))?;
+    update_user_fields(&users, user_id, doc! { "totp": totp }).await?;
+
+    Ok(Json(json!({
+        "secret": secret,
+        "otpauth_uri": otpauth_uri(&secret, &user.username),
+    })))
+}
+
+pub async fn confirm_totp(
+    state: &AppState,
+    user_id: &str,
+    code: &str,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let users = state.get_user_collection();
+    let user = find_user(&users, user_id).await?;
+    match &user.totp {
+        None => {
+            return Err(error_response(
+                StatusCode::BAD_REQUEST,
+                Some("No pending two-factor setup"),
+            ))
+        }
+        Some(totp) if totp.enabled => {
+            return Err(error_response(
+                StatusCode::CONFLICT,
+                Some("Two-factor authentication already enabled"),
+            ))
+        }
+        Some(_) => {}
+    }
+
+    if verify_second_factor(&users, &user, Some(code), None)
+        .await?
+        .is_none()
+    {
+        return Err(error_response(
+            StatusCode::UNAUTHORIZED,
+            Some("Invalid code"),
+        ));
+    }
+
+    let recovery_codes = generate_recovery_codes();
+    let hashes = recovery_codes
+        .iter()
+        .map(|code| hash_password(code, &state.password_policy))
+        .collect::<Result<Vec<String>, _>>()
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, None))?;
+    update_user_fields(
+        &users,
+        user_id,
+        doc! { "totp.enabled": true, "totp.recovery_codes": hashes },
+    )
+    .await?;
+
+    record_security_event(
+        &state.get_security_event_collection(),
+        SecurityEvent::new(user_id, SecurityEventKind::TwoFactorEnabled, None),
+    )
+    .await;
+
+    Ok(Json(json!({ "recovery_codes": recovery_codes })))
+}
+
+pub async fn disable_totp(
+    state: &AppState,
+    user_id: &str,
+    payload: DisableTotpPayload,
+) -> Result<(), Response> {
+    let users = state.get_user_collection();
+    let user = find_user(&users, user_id)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    verify_current_password(state, &user, &payload.password).await?;
+
+    if !user.totp.as_ref().is_some_and(|totp| totp.enabled) {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            Some("Two-factor authentication is not enabled"),
+        )
+        .into_response());
+    }
+    if verify_second_factor(
+        &users,
+        &user,
+        payload.code.as_deref(),
+        payload.recovery_code.as_deref(),
+    )
+    .await
+    .map_err(IntoResponse::into_response)?
+    .is_none()
+    {
+        let _ = record_login_failure(&state.redis, &user.username, None).await;
+        return Err(error_response(StatusCode::UNAUTHORIZED, Some("Invalid code")).into_response());
+    }
+
+    update_user_fields(&users, user_id, doc! { "totp": null })
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    record_security_event(
+        &state.get_security_event_collection(),
+        SecurityEvent::new(user_id, SecurityEventKind::TwoFactorDisabled, None),
+    )
+    .await;
+
+    Ok(())
+}
+
+/// Second step of a login with two-factor on. Wrong factors count towards the
+/// same lockout as wrong passwords, so a new challenge per correct password
+/// does not buy more guesses.
+pub async fn verify_two_factor_login(
+    state: &AppState,
+    payload: TwoFactorLoginPayload,
+    client_addr: Option<ClientAddr>,
+) -> Result<Json<Value>, Response> {
+    let session_error = |e| session_store_error(e).into_response();
+    let challenge = get_challenge(&state.redis, &payload.challenge)
+        .await
+        .map_err(session_error)?
+        .ok_or_else(|| {
+            error_response(
+                StatusCode::UNAUTHORIZED,
+                Some("Invalid or expired challenge"),
+            )
+            .into_response()
+        })?;
+
+    let users = state.get_user_collection();
+    let user = find_user(&users, &challenge.user_id)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let ip = client_addr.map(|ClientAddr(ip)| ip);
+    let lockout = login_lockout(&state.redis, &user.username, ip)
+        .await
+        .unwrap_or(None);
+    if let Some(retry_after) = lockout {
+        return Err(too_many_requests(retry_after));
+    }
+
+    let factor = verify_second_factor(
+        &users,
+        &user,
+        payload.code.as_deref(),
+        payload.recovery_code.as_deref(),
+    )
+    .await
+    .map_err(IntoResponse::into_response)?;
+    let Some(factor) = factor else {
+        let _ = record_login_failure(&state.redis, &user.username, ip).await;
+        record_failed_attempt(&state.redis, &payload.challenge)
+            .await
+            .map_err(session_error)?;
+        return Err(error_response(StatusCode::UNAUTHORIZED, Some("Invalid code")).into_response());
+    };
+
+    if !consume_challenge(&state.redis, &payload.challenge)
+        .await
+        .map_err(session_error)?
+    {
+        return Err(error_response(
+            StatusCode::UNAUTHORIZED,
+            Some("Invalid or expired challenge"),
+        )
+        .into_response());
+    }
+    let _ = reset_login_failures(&state.redis, &user.username).await;
+
+    if matches!(factor, SecondFactor::RecoveryCode) {
+        record_security_event(
+            &state.get_security_event_collection(),
+            SecurityEvent::new(
+                &user.uuid,
+                SecurityEventKind::RecoveryCodeUsed,
+                Some(&challenge.device_id),
+            ),
+        )
+        .await;
+    }
+
+    start_session(
+        user,
+        &challenge.device_id,
+        &challenge.metadata,
+        &state.keyring,
+        &state.redis,
+    )
+    .await
+    .map_err(IntoResponse::into_response)
+}
+
+pub async fn get_jwks(keyring: &KeyRing) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    Ok(Json(keyring.jwks()))
+}
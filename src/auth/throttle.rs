@@ -0,0 +1,93 @@
+use std::net::IpAddr;
+
+use redis::{AsyncCommands, Client};
+
+const FAILURE_WINDOW_SECS: i64 = 60 * 60;
+const USER_FAILURE_THRESHOLD: i64 = 5;
+const IP_FAILURE_THRESHOLD: i64 = 20;
+const BASE_LOCKOUT_SECS: i64 = 30;
+const MAX_LOCKOUT_SECS: i64 = 60 * 60;
+
+fn failures_key(scope: &str, id: &str) -> String {
+    format!("login_failures:{scope}:{id}")
+}
+
+fn lock_key(scope: &str, id: &str) -> String {
+    format!("login_lock:{scope}:{id}")
+}
+
+/// The address scope uses the trusted client address only, so that it can be
+/// neither dodged nor pointed at someone else through `X-Forwarded-For`.
+fn scopes(username: &str, ip: Option<IpAddr>) -> Vec<(&'static str, String, i64)> {
+    let mut scopes = vec![("user", username.to_string(), USER_FAILURE_THRESHOLD)];
+    if let Some(ip) = ip {
+        scopes.push(("ip", ip.to_string(), IP_FAILURE_THRESHOLD));
+    }
+    scopes
+}
+
+/// Lockout doubles with every failure past the threshold, capped at an hour.
+fn lockout_secs(failures: i64, threshold: i64) -> i64 {
+    let exponent = (failures - threshold).clamp(0, 16) as u32;
+    (BASE_LOCKOUT_SECS << exponent).min(MAX_LOCKOUT_SECS)
+}
+
+/// Seconds until the username or the client address may try again, if either
+/// is currently locked out.
+pub async fn login_lockout(
+    redis: &Client,
+    username: &str,
+    ip: Option<IpAddr>,
+) -> redis::RedisResult<Option<u64>> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    let mut retry_after = None;
+    for (scope, id, _) in scopes(username, ip) {
+        let ttl: i64 = conn.ttl(lock_key(scope, &id)).await?;
+        if ttl > 0 {
+            retry_after = retry_after.max(Some(ttl as u64));
+        }
+    }
+    Ok(retry_after)
+}
+
+pub async fn record_login_failure(
+    redis: &Client,
+    username: &str,
+    ip: Option<IpAddr>,
+) -> redis::RedisResult<()> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    for (scope, id, threshold) in scopes(username, ip) {
+        let key = failures_key(scope, &id);
+        let (failures,): (i64,) = redis::pipe()
+            .atomic()
+            .incr(&key, 1)
+            .expire(&key, FAILURE_WINDOW_SECS)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+        if failures >= threshold {
+            let _: () = conn
+                .set_ex(
+                    lock_key(scope, &id),
+                    failures,
+                    lockout_secs(failures, threshold) as u64,
+                )
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Clears the username counters after a successful login. The address
+/// counters are left alone so that an attacker holding one valid account
+/// cannot use it to reset the budget of an address guessing at others.
+pub async fn reset_login_failures(redis: &Client, username: &str) -> redis::RedisResult<()> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    redis::pipe()
+        .del(failures_key("user", username))
+        .ignore()
+        .del(lock_key("user", username))
+        .ignore()
+        .query_async(&mut conn)
+        .await
+}
@@ -1,29 +1,208 @@
 use axum::{http::StatusCode, Json};
+use mongodb::{bson::doc, Collection};
 use redis::Client;
-use serde_json::Value;
-use shuttle_runtime::SecretStore;
+use serde_json::{json, Value};
+use uuid::Uuid;
 
-use crate::auth::{
-    jwt::{create_access_token, create_refresh_token, decode_jwt},
-    whitelist::set_valid_jti,
+use crate::{
+    auth::{
+        jwt::{create_access_token, create_refresh_token, decode_jwt, Claims},
+        keyring::KeyRing,
+        model::{SecurityEvent, SecurityEventKind, SessionMetadata},
+        password::verify_password,
+        totp::{current_step, normalize_recovery_code, verify_code},
+        whitelist::{create_session, revoke_family, set_valid_jti},
+    },
+    keys::utils::{is_opk_pool_low, KeyTarget},
+    state::AppState,
+    user::models::{User, UserPrivate},
+    utils::error::{error_response, session_store_error},
 };
 
 pub async fn update_jwt(
     uuid: &str,
-    secret_store: &SecretStore,
+    device_id: &str,
+    family: &str,
+    keyring: &KeyRing,
     redis: &Client,
 ) -> Result<(String, String), (StatusCode, Json<Value>)> {
-    let access_token = create_access_token(uuid, secret_store);
-    let refresh_token = create_refresh_token(uuid, secret_store);
-    let access_claims = decode_jwt(&access_token, secret_store).unwrap();
-    let refresh_claims = decode_jwt(&refresh_token, secret_store).unwrap();
+    let access_token = create_access_token(uuid, device_id, family, keyring);
+    let refresh_token = create_refresh_token(uuid, device_id, family, keyring);
+    let access_claims = decode_jwt(&access_token, keyring).unwrap();
+    let refresh_claims = decode_jwt(&refresh_token, keyring).unwrap();
 
-    set_valid_jti(redis, &access_claims.sub, &access_claims.jti, "access")
-        .await
-        .unwrap();
+    set_valid_jti(
+        redis,
+        &access_claims.sub,
+        device_id,
+        family,
+        &access_claims.jti,
+        "access",
+    )
+    .await
+    .map_err(session_store_error)?;
 
-    set_valid_jti(redis, &refresh_claims.sub, &refresh_claims.jti, "refresh")
-        .await
-        .unwrap();
+    set_valid_jti(
+        redis,
+        &refresh_claims.sub,
+        device_id,
+        family,
+        &refresh_claims.jti,
+        "refresh",
+    )
+    .await
+    .map_err(session_store_error)?;
     Ok((access_token, refresh_token))
 }
+
+/// Opens a new device session for an authenticated user and returns the
+/// login response body.
+pub async fn start_session(
+    user: User,
+    device_id: &str,
+    metadata: &SessionMetadata,
+    keyring: &KeyRing,
+    redis: &Client,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    create_session(redis, &user.uuid, device_id, metadata)
+        .await
+        .map_err(session_store_error)?;
+    let (access_token, refresh_token) =
+        update_jwt(&user.uuid, device_id, &new_token_family(), keyring, redis).await?;
+
+    let opk_low = KeyTarget::for_session(&user, device_id)
+        .keys(&user)
+        .is_some_and(is_opk_pool_low);
+    let user_private = UserPrivate {
+        uuid: user.uuid,
+        username: user.username,
+        keys: user.keys,
+        description: user.description,
+        profile_picture: user.profile_picture,
+        pending_friend_requests: user.pending_friend_requests,
+        friends_requests: user.friends_requests,
+        friends: user.friends,
+        read_receipts: user.read_receipts,
+        opk_low,
+    };
+    Ok(Json(json!({
+        "user": user_private,
+        "device_id": device_id,
+        "token": {
+            "access": access_token,
+            "refresh": refresh_token
+        }
+    })))
+}
+
+/// Uses the device id supplied by the client so a device logging in again
+/// replaces its own session, or generates a fresh one.
+pub fn resolve_device_id(device_id: Option<String>) -> Result<String, (StatusCode, Json<Value>)> {
+    let Some(device_id) = device_id else {
+        return Ok(Uuid::new_v4().to_string());
+    };
+
+    let is_valid = !device_id.is_empty()
+        && device_id.len() <= 64
+        && device_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !is_valid {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            Some("Invalid device id"),
+        ));
+    }
+    Ok(device_id)
+}
+
+pub fn new_token_family() -> String {
+    Uuid::new_v4().to_string()
+}
+
+pub async fn record_security_event(events: &Collection<SecurityEvent>, event: SecurityEvent) {
+    let _ = events.insert_one(&event).await;
+}
+
+/// A rotated refresh token was presented again: revoke the whole family it
+/// belongs to and record the incident.
+pub async fn handle_refresh_reuse(state: &AppState, claims: &Claims) -> (StatusCode, Json<Value>) {
+    let _ = revoke_family(&state.redis, &claims.sub, &claims.device_id, &claims.family).await;
+    record_security_event(
+        &state.get_security_event_collection(),
+        SecurityEvent::new(
+            &claims.sub,
+            SecurityEventKind::RefreshTokenReuse,
+            Some(&claims.device_id),
+        ),
+    )
+    .await;
+    error_response(
+        StatusCode::UNAUTHORIZED,
+        Some("Refresh token reuse detected"),
+    )
+}
+
+pub enum SecondFactor {
+    Code,
+    RecoveryCode,
+}
+
+/// Checks a TOTP code or a recovery code for `user`, consuming it so that it
+/// cannot be presented a second time. Returns `None` when neither is valid.
+pub async fn verify_second_factor(
+    users: &Collection<User>,
+    user: &User,
+    code: Option<&str>,
+    recovery_code: Option<&str>,
+) -> Result<Option<SecondFactor>, (StatusCode, Json<Value>)> {
+    let Some(totp) = &user.totp else {
+        return Ok(None);
+    };
+
+    if let Some(code) = code {
+        let Some(step) = verify_code(&totp.secret, code, current_step()) else {
+            return Ok(None);
+        };
+        let step = step as i64;
+        let result = users
+            .update_one(
+                doc! {
+                    "uuid": &user.uuid,
+                    "$or": [
+                        { "totp.last_used_step": { "$lt": step } },
+                        { "totp.last_used_step": null },
+                    ],
+                },
+                doc! { "$set": { "totp.last_used_step": step } },
+            )
+            .await
+            .map_err(|_| {
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error"))
+            })?;
+        return Ok((result.modified_count == 1).then_some(SecondFactor::Code));
+    }
+
+    if let Some(recovery_code) = recovery_code {
+        let recovery_code = normalize_recovery_code(recovery_code);
+        let Some(hash) = totp
+            .recovery_codes
+            .iter()
+            .find(|hash| verify_password(&recovery_code, hash).unwrap_or(false))
+        else {
+            return Ok(None);
+        };
+        let result = users
+            .update_one(
+                doc! { "uuid": &user.uuid, "totp.recovery_codes": hash },
+                doc! { "$pull": { "totp.recovery_codes": hash } },
+            )
+            .await
+            .map_err(|_| {
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error"))
+            })?;
+        return Ok((result.modified_count == 1).then_some(SecondFactor::RecoveryCode));
+    }
+
+    Ok(None)
+}
@@ -1,34 +1,263 @@
+use std::collections::HashMap;
+
 use redis::{AsyncCommands, Client};
 
-pub async fn set_valid_jti(
-    redis: &Client,
-    user_id: &str,
-    jti: &str,
-    token_type: &str,
-) -> redis::RedisResult<()> {
+use crate::auth::{jwt::REFRESH_TOKEN_TTL_SECS, model::SessionMetadata};
+
+fn session_key(user_id: &str, device_id: &str) -> String {
+    format!("session:{user_id}:{device_id}")
+}
+
+fn sessions_key(user_id: &str) -> String {
+    format!("sessions:{user_id}")
+}
+
+fn rotated_key(family: &str) -> String {
+    format!("refresh_family:{family}")
+}
+
+fn check_token_type(token_type: &str) -> redis::RedisResult<()> {
     if token_type != "access" && token_type != "refresh" {
         return Err(redis::RedisError::from((
             redis::ErrorKind::TypeError,
             "Invalid token type",
         )));
     }
+    Ok(())
+}
+
+pub async fn set_valid_jti(
+    redis: &Client,
+    user_id: &str,
+    device_id: &str,
+    family: &str,
+    jti: &str,
+    token_type: &str,
+) -> redis::RedisResult<()> {
+    check_token_type(token_type)?;
     let mut conn = redis.clone().get_multiplexed_async_connection().await?;
-    conn.set(format!("{token_type}_jti:{user_id}"), jti).await
+    let session = session_key(user_id, device_id);
+    let sessions = sessions_key(user_id);
+    redis::pipe()
+        .atomic()
+        .hset_multiple(
+            &session,
+            &[
+                (format!("{token_type}_jti").as_str(), jti),
+                ("family", family),
+            ],
+        )
+        .ignore()
+        .expire(&session, REFRESH_TOKEN_TTL_SECS)
+        .ignore()
+        .sadd(&sessions, device_id)
+        .ignore()
+        .expire(&sessions, REFRESH_TOKEN_TTL_SECS)
+        .ignore()
+        .query_async(&mut conn)
+        .await
 }
 
 pub async fn is_jti_valid(
     redis: &Client,
     user_id: &str,
+    device_id: &str,
     jti: &str,
     token_type: &str,
 ) -> redis::RedisResult<bool> {
-    if token_type != "access" && token_type != "refresh" {
-        return Err(redis::RedisError::from((
-            redis::ErrorKind::TypeError,
-            "Invalid token type",
-        )));
-    }
+    check_token_type(token_type)?;
     let mut conn = redis.clone().get_multiplexed_async_connection().await?;
-    let expected: Option<String> = conn.get(format!("{token_type}_jti:{user_id}")).await?;
+    let expected: Option<String> = conn
+        .hget(session_key(user_id, device_id), format!("{token_type}_jti"))
+        .await?;
     Ok(expected.as_deref() == Some(jti))
 }
+
+/// Marks a refresh jti as consumed within its family. Returns `false` when the
+/// jti had already been rotated, which means the token is being replayed.
+pub async fn rotate_refresh_jti(
+    redis: &Client,
+    family: &str,
+    jti: &str,
+) -> redis::RedisResult<bool> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    let key = rotated_key(family);
+    let (added,): (i64,) = redis::pipe()
+        .atomic()
+        .sadd(&key, jti)
+        .expire(&key, REFRESH_TOKEN_TTL_SECS)
+        .ignore()
+        .query_async(&mut conn)
+        .await?;
+    Ok(added == 1)
+}
+
+pub async fn is_refresh_jti_rotated(
+    redis: &Client,
+    family: &str,
+    jti: &str,
+) -> redis::RedisResult<bool> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    conn.sismember(rotated_key(family), jti).await
+}
+
+/// Drops the device session only if it still belongs to `family`, so a replayed
+/// token from an old family cannot kill a newer login on the same device.
+pub async fn revoke_family(
+    redis: &Client,
+    user_id: &str,
+    device_id: &str,
+    family: &str,
+) -> redis::RedisResult<bool> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    let script = redis::Script::new(
+        r"
+        if redis.call('HGET', KEYS[1], 'family') == ARGV[1] then
+            redis.call('DEL', KEYS[1])
+            redis.call('SREM', KEYS[2], ARGV[2])
+            return 1
+        end
+        return 0
+        ",
+    );
+    let revoked: i64 = script
+        .key(session_key(user_id, device_id))
+        .key(sessions_key(user_id))
+        .arg(family)
+        .arg(device_id)
+        .invoke_async(&mut conn)
+        .await?;
+    Ok(revoked == 1)
+}
+
+pub async fn revoke_session(
+    redis: &Client,
+    user_id: &str,
+    device_id: &str,
+) -> redis::RedisResult<()> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    redis::pipe()
+        .atomic()
+        .del(session_key(user_id, device_id))
+        .ignore()
+        .srem(sessions_key(user_id), device_id)
+        .ignore()
+        .query_async(&mut conn)
+        .await
+}
+
+pub async fn revoke_all_sessions(redis: &Client, user_id: &str) -> redis::RedisResult<()> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    let device_ids: Vec<String> = conn.smembers(sessions_key(user_id)).await?;
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for device_id in &device_ids {
+        pipe.del(session_key(user_id, device_id)).ignore();
+    }
+    pipe.del(sessions_key(user_id)).ignore();
+    pipe.query_async(&mut conn).await
+}
+
+/// Starts a fresh session for the device, replacing whatever it held before.
+pub async fn create_session(
+    redis: &Client,
+    user_id: &str,
+    device_id: &str,
+    metadata: &SessionMetadata,
+) -> redis::RedisResult<()> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    let session = session_key(user_id, device_id);
+    let now = chrono::Utc::now().timestamp().to_string();
+
+    let mut fields = vec![("created_at", now.clone()), ("last_used_at", now)];
+    if let Some(device_name) = &metadata.device_name {
+        fields.push(("device_name", device_name.clone()));
+    }
+    if let Some(user_agent) = &metadata.user_agent {
+        fields.push(("user_agent", user_agent.clone()));
+    }
+    if let Some(ip) = &metadata.ip {
+        fields.push(("ip", ip.clone()));
+    }
+
+    redis::pipe()
+        .atomic()
+        .del(&session)
+        .ignore()
+        .hset_multiple(&session, &fields)
+        .ignore()
+        .expire(&session, REFRESH_TOKEN_TTL_SECS)
+        .ignore()
+        .query_async(&mut conn)
+        .await
+}
+
+/// Bumps `last_used_at` without resurrecting a session revoked in the meantime.
+pub async fn touch_session(
+    redis: &Client,
+    user_id: &str,
+    device_id: &str,
+) -> redis::RedisResult<()> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    let script = redis::Script::new(
+        r"
+        if redis.call('EXISTS', KEYS[1]) == 1 then
+            redis.call('HSET', KEYS[1], 'last_used_at', ARGV[1])
+        end
+        return 0
+        ",
+    );
+    let _: i64 = script
+        .key(session_key(user_id, device_id))
+        .arg(chrono::Utc::now().timestamp())
+        .invoke_async(&mut conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_sessions(
+    redis: &Client,
+    user_id: &str,
+) -> redis::RedisResult<Vec<(String, HashMap<String, String>)>> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    let device_ids: Vec<String> = conn.smembers(sessions_key(user_id)).await?;
+
+    let mut sessions = Vec::with_capacity(device_ids.len());
+    for device_id in device_ids {
+        let fields: HashMap<String, String> =
+            conn.hgetall(session_key(user_id, &device_id)).await?;
+        if fields.is_empty() {
+            let _: () = conn.srem(sessions_key(user_id), &device_id).await?;
+        } else {
+            sessions.push((device_id, fields));
+        }
+    }
+    Ok(sessions)
+}
+
+pub async fn session_exists(
+    redis: &Client,
+    user_id: &str,
+    device_id: &str,
+) -> redis::RedisResult<bool> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    conn.exists(session_key(user_id, device_id)).await
+}
+
+pub async fn revoke_other_sessions(
+    redis: &Client,
+    user_id: &str,
+    keep_device_id: &str,
+) -> redis::RedisResult<()> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    let device_ids: Vec<String> = conn.smembers(sessions_key(user_id)).await?;
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for device_id in device_ids.iter().filter(|id| *id != keep_device_id) {
+        pipe.del(session_key(user_id, device_id)).ignore();
+        pipe.srem(sessions_key(user_id), device_id).ignore();
+    }
+    pipe.query_async(&mut conn).await
+}
@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::user::models::MessageInfo;
+
+/// Something a user should learn about the next time they sync, kept in the
+/// `events` collection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub uuid: String,
+    pub recipient: String,
+    /// Position in the recipient's queue, strictly increasing; the sync order.
+    #[serde(default)]
+    pub seq: i64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+    pub created_at: i64,
+}
+
+impl Event {
+    pub fn new(recipient: &str, seq: i64, kind: EventKind) -> Self {
+        Self {
+            uuid: Uuid::new_v4().to_string(),
+            recipient: recipient.to_string(),
+            seq,
+            kind,
+            created_at: chrono::Utc::now().timestamp(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    /// A message was queued for the user.
+    MessageReceived { message: MessageInfo },
+    /// `user_id` asked to become friends.
+    FriendRequestReceived { user_id: String },
+    /// `user_id` accepted a friend request.
+    FriendRequestAccepted { user_id: String },
+    /// A friend replaced their identity key; their safety number changed.
+    IdentityKeyChanged { user_id: String, ik_version: i64 },
+    /// A friend registered or removed a device; refetch their device list.
+    DeviceListChanged { user_id: String },
+    /// `user_id` fetched a message the user sent, on `device_id`.
+    MessageDelivered {
+        message_id: String,
+        user_id: String,
+        device_id: String,
+    },
+    /// `user_id` read a message the user sent.
+    MessageRead { message_id: String, user_id: String },
+}
+
+impl EventKind {
+    /// Same as the serialized `type` tag, used as the SSE event name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::MessageReceived { .. } => "message_received",
+            Self::FriendRequestReceived { .. } => "friend_request_received",
+            Self::FriendRequestAccepted { .. } => "friend_request_accepted",
+            Self::IdentityKeyChanged { .. } => "identity_key_changed",
+            Self::DeviceListChanged { .. } => "device_list_changed",
+            Self::MessageDelivered { .. } => "message_delivered",
+            Self::MessageRead { .. } => "message_read",
+        }
+    }
+}
+
+impl Event {
+    /// Whether a connection of `device_id` should see the event. Messages
+    /// addressed to another device are skipped.
+    pub fn is_for_device(&self, device_id: &str) -> bool {
+        match &self.kind {
+            EventKind::MessageReceived { message } => message
+                .receiver_device
+                .as_deref()
+                .is_none_or(|receiver_device| receiver_device == device_id),
+            _ => true,
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use axum::{http::StatusCode, Json};
+use futures::stream::TryStreamExt;
+use mongodb::{
+    bson::{doc, to_document, DateTime, Document},
+    error::{ErrorKind, InsertManyError},
+    options::IndexOptions,
+    Collection, IndexModel,
+};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    event::models::{Event, EventKind},
+    realtime::hub::Push,
+    state::AppState,
+    utils::error::error_response,
+};
+
+/// Upper bound on the events returned by a single sync.
+pub const MAX_EVENTS_PER_PAGE: i64 = 100;
+/// How long an event stays queued; clients offline for longer resync fully.
+pub const EVENT_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+pub async fn create_indexes(events: &Collection<Event>) -> mongodb::error::Result<()> {
+    events
+        .create_indexes([
+            IndexModel::builder()
+                .keys(doc! { "recipient": 1, "seq": 1 })
+                .build(),
+            // Enforces one event per position; events queued before positions
+            // existed have none.
+            IndexModel::builder()
+                .keys(doc! { "recipient": 1, "seq": -1 })
+                .options(
+                    IndexOptions::builder()
+                        .unique(true)
+                        .partial_filter_expression(doc! { "seq": { "$exists": true } })
+                        .build(),
+                )
+                .build(),
+            IndexModel::builder()
+                .keys(doc! { "uuid": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            IndexModel::builder()
+                .keys(doc! { "expires_at": 1 })
+                .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+                .build(),
+        ])
+        .await?;
+    Ok(())
+}
+
+/// Position of the newest event visible in the queue of `recipient`, `0`
+/// when it is empty.
+async fn last_seq(events: &Collection<Event>, recipient: &str) -> mongodb::error::Result<i64> {
+    Ok(events
+        .find_one(doc! { "recipient": recipient })
+        .sort(doc! { "seq": -1 })
+        .await?
+        .map_or(0, |event| event.seq))
+}
+
+/// Index of the event whose position a concurrent writer took first, when
+/// that is why an ordered insert stopped.
+fn taken_position(error: &mongodb::error::Error) -> Option<usize> {
+    match error.kind.as_ref() {
+        ErrorKind::InsertMany(InsertManyError {
+            write_errors: Some(errors),
+            ..
+        }) => match errors.as_slice() {
+            [error] if error.code == 11000 => Some(error.index),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Queues the same event for every recipient, then hands it to their live
+/// connections.
+pub async fn push_event(
+    state: &AppState,
+    recipients: &[String],
+    kind: EventKind,
+) -> mongodb::error::Result<()> {
+    push_events(
+        state,
+        recipients
+            .iter()
+            .map(|recipient| (recipient.clone(), kind.clone()))
+            .collect(),
+    )
+    .await
+}
+
+/// Queues a batch of `(recipient, kind)` events with a single insert, then
+/// hands them to their live connections.
+///
+/// Each event takes the position right after the newest one in its
+/// recipient's queue, and the unique `{recipient, seq}` index turns a position
+/// taken concurrently into a retry. An event thus only becomes visible once
+/// every event before it is, so readers resuming after a `seq` never skip one.
+pub async fn push_events(
+    state: &AppState,
+    events: Vec<(String, EventKind)>,
+) -> mongodb::error::Result<()> {
+    let collection = state.get_event_collection();
+    let stored_collection = collection.clone_with_type::<Document>();
+    // `expires_at` only exists in the database, for the TTL index.
+    let expires_at = DateTime::from_system_time(SystemTime::now() + EVENT_TTL);
+
+    let mut pending = events;
+    let mut queued = Vec::with_capacity(pending.len());
+    let result = loop {
+        if pending.is_empty() {
+            break Ok(());
+        }
+        let mut next_seqs: HashMap<&str, i64> = HashMap::new();
+        for (recipient, _) in &pending {
+            if !next_seqs.contains_key(recipient.as_str()) {
+                next_seqs.insert(recipient, last_seq(&collection, recipient).await?);
+            }
+        }
+        let batch: Vec<Event> = pending
+            .iter()
+            .map(|(recipient, kind)| {
+                let seq = next_seqs
+                    .get_mut(recipient.as_str())
+                    .expect("every recipient has a position");
+                *seq += 1;
+                Event::new(recipient, *seq, kind.clone())
+            })
+            .collect();
+        let stored = batch
+            .iter()
+            .map(|event| {
+                let mut stored = to_document(event)?;
+                stored.insert("expires_at", expires_at);
+                Ok(stored)
+            })
+            .collect::<Result<Vec<Document>, mongodb::bson::ser::Error>>()?;
+
+        // The insert is ordered: everything before a taken position is queued
+        // and the rest retries after the newest events.
+        let inserted = match stored_collection.insert_many(stored).await {
+            Ok(_) => batch.len(),
+            Err(e) => match taken_position(&e) {
+                Some(index) => index,
+                None => break Err(e),
+            },
+        };
+        pending.drain(..inserted);
+        queued.extend(batch.into_iter().take(inserted));
+    };
+
+    state
+        .hub
+        .push_many(
+            queued
+                .into_iter()
+                .map(|event| (event.recipient.clone(), None, Push::Event(event)))
+                .collect(),
+        )
+        .await;
+    result
+}
+
+/// Drops the queue of a deleted user.
+pub async fn delete_events(state: &AppState, user_id: &str) -> mongodb::error::Result<()> {
+    state
+        .get_event_collection()
+        .delete_many(doc! { "recipient": user_id })
+        .await?;
+    Ok(())
+}
+
+/// Events for `user_id` in the order they were queued, starting right after
+/// the event `after` when given.
+pub async fn get_events(
+    events: Collection<Event>,
+    user_id: &str,
+    after: Option<&str>,
+) -> Result<Vec<Event>, (StatusCode, Json<Value>)> {
+    let mut filter = doc! { "recipient": user_id };
+    if let Some(after) = after {
+        let anchor = events
+            .find_one(doc! { "uuid": after, "recipient": user_id })
+            .await
+            .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?
+            .ok_or(error_response(
+                StatusCode::NOT_FOUND,
+                Some("Event not found"),
+            ))?;
+        filter.insert("seq", doc! { "$gt": anchor.seq });
+    }
+
+    events
+        .find(filter)
+        .sort(doc! { "seq": 1 })
+        .limit(MAX_EVENTS_PER_PAGE)
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?
+        .try_collect()
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))
+}
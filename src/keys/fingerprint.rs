@@ -0,0 +1,114 @@
+use sha2::{Digest, Sha512};
+
+/// Format version mixed into every hash, as in libsignal's numeric fingerprint.
+const FINGERPRINT_VERSION: u16 = 0;
+/// Version byte leading the scannable payload.
+const SCANNABLE_VERSION: u8 = 0;
+const ITERATIONS: usize = 5200;
+/// libsignal serializes Curve25519 public keys behind this type byte.
+const DJB_KEY_TYPE: u8 = 0x05;
+const SCANNABLE_FINGERPRINT_LENGTH: usize = 32;
+
+/// Iterated SHA-512 over a user's identity key and stable identifier (their
+/// uuid), following Signal's numeric fingerprint scheme: starting from
+/// `version || key || id`, each of the [`ITERATIONS`] rounds hashes the
+/// previous value followed by the key.
+fn fingerprint_hash(user_id: &str, ik_pub: &[u8; 32]) -> [u8; 64] {
+    let mut public_key = [0u8; 33];
+    public_key[0] = DJB_KEY_TYPE;
+    public_key[1..].copy_from_slice(ik_pub);
+
+    let mut hash = [
+        FINGERPRINT_VERSION.to_be_bytes().as_slice(),
+        &public_key,
+        user_id.as_bytes(),
+    ]
+    .concat();
+    for _ in 0..ITERATIONS {
+        hash = Sha512::new()
+            .chain_update(&hash)
+            .chain_update(public_key)
+            .finalize()
+            .to_vec();
+    }
+    hash.try_into().expect("SHA-512 digests are 64 bytes")
+}
+
+/// 30 digits: six 5-byte chunks of the hash, each reduced mod 100000.
+fn displayable(hash: &[u8; 64]) -> String {
+    hash[..30]
+        .chunks(5)
+        .map(|chunk| {
+            let value = chunk
+                .iter()
+                .fold(0u64, |acc, byte| (acc << 8) | *byte as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect()
+}
+
+/// The 60 digit safety number shared by two users. Both halves are sorted so
+/// each side computes the same string.
+pub fn safety_number(
+    local_id: &str,
+    local_ik: &[u8; 32],
+    remote_id: &str,
+    remote_ik: &[u8; 32],
+) -> String {
+    let mut halves = [
+        displayable(&fingerprint_hash(local_id, local_ik)),
+        displayable(&fingerprint_hash(remote_id, remote_ik)),
+    ];
+    halves.sort();
+    halves.concat()
+}
+
+/// Bytes meant to be rendered as a QR code by `local_id`: a version byte,
+/// then the first 32 bytes of the local hash, then those of the remote hash.
+/// The scanning side swaps the two halves before comparing.
+pub fn scannable(
+    local_id: &str,
+    local_ik: &[u8; 32],
+    remote_id: &str,
+    remote_ik: &[u8; 32],
+) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(1 + 2 * SCANNABLE_FINGERPRINT_LENGTH);
+    bytes.push(SCANNABLE_VERSION);
+    bytes.extend_from_slice(&fingerprint_hash(local_id, local_ik)[..SCANNABLE_FINGERPRINT_LENGTH]);
+    bytes
+        .extend_from_slice(&fingerprint_hash(remote_id, remote_ik)[..SCANNABLE_FINGERPRINT_LENGTH]);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity_key(hex: &str) -> [u8; 32] {
+        let bytes: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        assert_eq!(bytes[0], DJB_KEY_TYPE);
+        bytes[1..].try_into().unwrap()
+    }
+
+    /// `fingerprint_test_v1` of libsignal's `fingerprint.rs`.
+    #[test]
+    fn matches_libsignal_displayable_fingerprint() {
+        let alice =
+            identity_key("0506863bc66d02b40d27b8d49ca7c09e9239236f9d7d25d6fcca5ce13c7064d868");
+        let bob =
+            identity_key("05f781b6fb32fed9ba1cf2de978d4d5da28dc34046ae814402b5c0dbd96fda907b");
+        let expected = "300354477692869396892869876765458257569162576843440918079131";
+
+        assert_eq!(
+            safety_number("+14152222222", &alice, "+14153333333", &bob),
+            expected
+        );
+        assert_eq!(
+            safety_number("+14153333333", &bob, "+14152222222", &alice),
+            expected
+        );
+    }
+}
@@ -0,0 +1,6 @@
+pub mod fingerprint;
+pub mod models;
+pub mod payload;
+pub mod services;
+pub mod utils;
+pub mod xeddsa;
@@ -0,0 +1,102 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use shuttle_runtime::SecretStore;
+
+use crate::{
+    transparency::models::TransparencyProof,
+    user::models::{Device, OneTimePreKeyPublic},
+};
+
+/// What a sender needs to run X3DH against `user_id`, or against one of their
+/// devices when `device_id` is set. `opk` is `None` once the one-time prekey
+/// pool is exhausted; X3DH then runs without it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PreKeyBundle {
+    pub user_id: String,
+    pub device_id: Option<String>,
+    pub ik_pub: [u8; 32],
+    /// Of the device's key set when `device_id` is set.
+    pub ik_version: i64,
+    pub spk_pub: [u8; 32],
+    pub spk_signature: Vec<u8>,
+    pub spk_id: String,
+    pub spk_created_at: Option<i64>,
+    pub opk: Option<OneTimePreKeyPublic>,
+    /// Inclusion of `(user_id, device_id, ik_pub, ik_version)` in the key
+    /// transparency log. `None` only when the log could not be read.
+    pub transparency: Option<TransparencyProof>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub device_id: String,
+    pub name: Option<String>,
+    pub ik_pub: [u8; 32],
+    pub created_at: i64,
+}
+
+impl From<&Device> for DeviceInfo {
+    fn from(device: &Device) -> Self {
+        Self {
+            device_id: device.device_id.clone(),
+            name: device.name.clone(),
+            ik_pub: device.keys.ik_pub,
+            created_at: device.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpkCount {
+    pub count: usize,
+    pub max: usize,
+    /// Set once the pool drops below the low watermark; the client should
+    /// upload a fresh batch.
+    pub low: bool,
+}
+
+/// Safety number between the caller and `user_id`. `scannable` is the base64
+/// payload for the caller's QR code.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub user_id: String,
+    pub ik_version: i64,
+    pub safety_number: String,
+    pub scannable: String,
+}
+
+/// Signed prekey lifetime rules, read from the `SecretStore` at startup.
+#[derive(Debug, Clone)]
+pub struct KeyPolicy {
+    /// How long a rotated-out signed prekey is still accepted.
+    pub spk_grace_period_secs: i64,
+    /// Bundles are refused once their signed prekey is older than this.
+    pub spk_max_age_secs: i64,
+}
+
+impl Default for KeyPolicy {
+    fn default() -> Self {
+        Self {
+            spk_grace_period_secs: 7 * 24 * 60 * 60,
+            spk_max_age_secs: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl KeyPolicy {
+    pub fn from_secret_store(secret_store: &SecretStore) -> anyhow::Result<Self> {
+        let defaults = Self::default();
+        let parse = |key: &str, default: i64| -> anyhow::Result<i64> {
+            secret_store
+                .get(key)
+                .filter(|v| !v.trim().is_empty())
+                .map_or(Ok(default), |v| {
+                    v.trim().parse().with_context(|| format!("invalid {key}"))
+                })
+        };
+        Ok(Self {
+            spk_grace_period_secs: parse("SPK_GRACE_PERIOD_SECS", defaults.spk_grace_period_secs)?,
+            spk_max_age_secs: parse("SPK_MAX_AGE_SECS", defaults.spk_max_age_secs)?,
+        })
+    }
+}
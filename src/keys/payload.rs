@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+use crate::user::models::OneTimePreKeyPublic;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpkUploadPayload {
+    pub opk_pub: Vec<OneTimePreKeyPublic>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SpkRotationPayload {
+    pub spk_id: Option<String>,
+    pub spk_pub: [u8; 32],
+    pub spk_signature: Vec<u8>,
+}
+
+/// Full key set replacing the caller's identity, e.g. after a reinstall.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdentityKeyPayload {
+    pub password: String,
+    pub ik_pub: [u8; 32],
+    pub spk_pub: [u8; 32],
+    pub spk_signature: Vec<u8>,
+    pub opk_pub: Vec<OneTimePreKeyPublic>,
+}
+
+/// Key set of the device the request is made from.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceRegistrationPayload {
+    pub name: Option<String>,
+    pub ik_pub: [u8; 32],
+    pub spk_pub: [u8; 32],
+    pub spk_signature: Vec<u8>,
+    pub opk_pub: Vec<OneTimePreKeyPublic>,
+}
@@ -0,0 +1,548 @@
+use axum::{http::StatusCode, Json};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use mongodb::{
+    bson::{doc, to_bson},
+    Collection,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    auth::{password::verify_password, whitelist::revoke_session},
+    event::{models::EventKind, services::push_event},
+    keys::{
+        fingerprint,
+        models::{DeviceInfo, Fingerprint, KeyPolicy, OpkCount, PreKeyBundle},
+        payload::{
+            DeviceRegistrationPayload, IdentityKeyPayload, OpkUploadPayload, SpkRotationPayload,
+        },
+        utils::{
+            check_device_slot, is_opk_pool_low, validate_opks, verify_signed_prekey, KeyTarget,
+            MAX_DEVICES, MAX_OPK_COUNT,
+        },
+    },
+    state::AppState,
+    transparency,
+    user::{
+        models::{Device, Key, PreviousSignedPreKey, User},
+        utils::find_user,
+    },
+    utils::error::{error_response, session_store_error},
+};
+
+/// Hands out the prekey bundle of the target's account keys, or of one of
+/// their devices, popping one one-time prekey in the same atomic update so two
+/// senders can never be given the same one.
+///
+/// Bundles whose signed prekey is older than the policy allows are refused
+/// before anything is popped.
+pub async fn get_bundle(
+    state: &AppState,
+    user_id: &str,
+    target_id: &str,
+    device_id: Option<&str>,
+) -> Result<PreKeyBundle, (StatusCode, Json<Value>)> {
+    let users = state.get_user_collection();
+    let key_target = match device_id {
+        Some(device_id) => KeyTarget::Device(device_id.to_string()),
+        None => KeyTarget::Account,
+    };
+    let oldest_allowed = chrono::Utc::now().timestamp() - state.key_policy.spk_max_age_secs;
+    let mut filter = key_target.filter(
+        target_id,
+        doc! { "spk_created_at": { "$not": { "$lt": oldest_allowed } } },
+    );
+    filter.insert("friends", user_id);
+    let target = users
+        .find_one_and_update(filter, doc! { "$pop": { key_target.path("opk_pub"): -1 } })
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
+
+    let Some(target) = target else {
+        let target = find_user(&users, target_id).await?;
+        if !target.friends.iter().any(|id| id == user_id) {
+            return Err(error_response(
+                StatusCode::FORBIDDEN,
+                Some("Not friends with this user"),
+            ));
+        }
+        if key_target.keys(&target).is_none() {
+            return Err(error_response(
+                StatusCode::NOT_FOUND,
+                Some("Device not found"),
+            ));
+        }
+        return Err(error_response(
+            StatusCode::CONFLICT,
+            Some("Signed prekey has expired"),
+        ));
+    };
+
+    let user_id = target.uuid.clone();
+    let ik_version = match &key_target {
+        KeyTarget::Account => target.ik_version,
+        KeyTarget::Device(device_id) => target
+            .device(device_id)
+            .map_or(target.ik_version, |device| device.ik_version),
+    };
+    let keys = key_target.clone().into_keys(target).ok_or(error_response(
+        StatusCode::NOT_FOUND,
+        Some("Device not found"),
+    ))?;
+    let transparency = transparency::services::prove(
+        state,
+        &user_id,
+        key_target.device_id(),
+        keys.ik_pub,
+        ik_version,
+    )
+    .await
+    .ok()
+    .flatten();
+    Ok(PreKeyBundle {
+        user_id,
+        device_id: key_target.device_id().map(str::to_string),
+        ik_pub: keys.ik_pub,
+        ik_version,
+        spk_pub: keys.spk_pub,
+        spk_signature: keys.spk_signature,
+        spk_id: keys.spk_id,
+        spk_created_at: keys.spk_created_at,
+        opk: keys.opk_pub.into_iter().next(),
+        transparency,
+    })
+}
+
+/// One-time prekey pool of the key set used by the calling session.
+pub async fn get_opk_count(
+    users: Collection<User>,
+    user_id: &str,
+    device_id: &str,
+) -> Result<OpkCount, (StatusCode, Json<Value>)> {
+    let user = find_user(&users, user_id).await?;
+    let key_target = KeyTarget::for_session(&user, device_id);
+    let keys = key_target.keys(&user).ok_or(error_response(
+        StatusCode::NOT_FOUND,
+        Some("Device not found"),
+    ))?;
+    Ok(OpkCount {
+        count: keys.opk_pub.len(),
+        max: MAX_OPK_COUNT,
+        low: is_opk_pool_low(keys),
+    })
+}
+
+/// Appends one-time prekeys to the caller's pool. The id uniqueness and the
+/// pool cap are part of the update filter so concurrent uploads cannot break
+/// either.
+pub async fn upload_opks(
+    users: Collection<User>,
+    user_id: &str,
+    device_id: &str,
+    payload: OpkUploadPayload,
+) -> Result<OpkCount, (StatusCode, Json<Value>)> {
+    let opks = payload.opk_pub;
+    if opks.is_empty() {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            Some("No one-time prekeys provided"),
+        ));
+    }
+    validate_opks(&opks)?;
+
+    let ids: Vec<&str> = opks.iter().map(|opk| opk.uuid.as_str()).collect();
+
+    let opk_docs = opks
+        .iter()
+        .map(to_bson)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, None))?;
+    let max_existing = MAX_OPK_COUNT - opks.len();
+
+    let user = find_user(&users, user_id).await?;
+    let key_target = KeyTarget::for_session(&user, device_id);
+    // No element at `max_existing` means the pool holds at most that many.
+    let filter = key_target.filter(
+        user_id,
+        doc! {
+            "opk_pub.uuid": { "$nin": &ids },
+            format!("opk_pub.{max_existing}"): { "$exists": false },
+        },
+    );
+    let result = users
+        .update_one(
+            filter,
+            doc! { "$push": { key_target.path("opk_pub"): { "$each": opk_docs } } },
+        )
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
+
+    if result.matched_count == 0 {
+        let user = find_user(&users, user_id).await?;
+        let keys = key_target.keys(&user).ok_or(error_response(
+            StatusCode::NOT_FOUND,
+            Some("Device not found"),
+        ))?;
+        if keys
+            .opk_pub
+            .iter()
+            .any(|opk| ids.contains(&opk.uuid.as_str()))
+        {
+            return Err(error_response(
+                StatusCode::CONFLICT,
+                Some("One-time prekey id already in use"),
+            ));
+        }
+        return Err(error_response(
+            StatusCode::CONFLICT,
+            Some("One-time prekey pool is full"),
+        ));
+    }
+
+    get_opk_count(users, user_id, device_id).await
+}
+
+/// Replaces the caller's signed prekey. The outgoing one is kept, for the
+/// grace period of the policy, so initial messages already built against it
+/// are still accepted.
+pub async fn rotate_spk(
+    users: Collection<User>,
+    key_policy: &KeyPolicy,
+    user_id: &str,
+    device_id: &str,
+    payload: SpkRotationPayload,
+) -> Result<Value, (StatusCode, Json<Value>)> {
+    let user = find_user(&users, user_id).await?;
+    let key_target = KeyTarget::for_session(&user, device_id);
+    let keys = key_target.clone().into_keys(user).ok_or(error_response(
+        StatusCode::NOT_FOUND,
+        Some("Device not found"),
+    ))?;
+
+    verify_signed_prekey(&keys.ik_pub, &payload.spk_pub, &payload.spk_signature)?;
+
+    let now = chrono::Utc::now().timestamp();
+    let spk_id = payload
+        .spk_id
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    if spk_id.is_empty()
+        || keys.spk_id == spk_id
+        || keys.previous_spks.iter().any(|spk| spk.spk_id == spk_id)
+    {
+        return Err(error_response(
+            StatusCode::CONFLICT,
+            Some("Signed prekey id already in use"),
+        ));
+    }
+
+    let mut previous_spks: Vec<PreviousSignedPreKey> = keys
+        .previous_spks
+        .into_iter()
+        .filter(|spk| spk.expires_at > now)
+        .collect();
+    previous_spks.push(PreviousSignedPreKey {
+        spk_id: keys.spk_id,
+        spk_pub: keys.spk_pub,
+        spk_signature: keys.spk_signature,
+        created_at: keys.spk_created_at,
+        expires_at: now + key_policy.spk_grace_period_secs,
+    });
+
+    let current_spk = to_bson(&keys.spk_pub)
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, None))?;
+    let update = doc! {
+        key_target.path("spk_id"): &spk_id,
+        key_target.path("spk_pub"): to_bson(&payload.spk_pub)
+            .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, None))?,
+        key_target.path("spk_signature"): to_bson(&payload.spk_signature)
+            .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, None))?,
+        key_target.path("spk_created_at"): now,
+        key_target.path("previous_spks"): to_bson(&previous_spks)
+            .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, None))?,
+    };
+
+    let result = users
+        .update_one(
+            key_target.filter(user_id, doc! { "spk_pub": current_spk }),
+            doc! { "$set": update },
+        )
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
+    if result.matched_count == 0 {
+        return Err(error_response(
+            StatusCode::CONFLICT,
+            Some("Signed prekey was rotated concurrently"),
+        ));
+    }
+
+    Ok(json!({
+        "spk_id": spk_id,
+        "spk_created_at": now,
+    }))
+}
+
+/// Replaces the caller's whole key set. When the identity key itself changes
+/// the version is bumped and every friend is told, so their clients can warn
+/// that the safety number changed.
+pub async fn replace_identity_key(
+    state: &AppState,
+    user_id: &str,
+    payload: IdentityKeyPayload,
+) -> Result<Value, (StatusCode, Json<Value>)> {
+    let users = state.get_user_collection();
+    let user = find_user(&users, user_id).await?;
+
+    if !verify_password(&payload.password, &user.password_hash).unwrap_or(false) {
+        return Err(error_response(
+            StatusCode::UNAUTHORIZED,
+            Some("Invalid credentials"),
+        ));
+    }
+
+    verify_signed_prekey(&payload.ik_pub, &payload.spk_pub, &payload.spk_signature)?;
+    validate_opks(&payload.opk_pub)?;
+
+    let payload_ik = payload.ik_pub;
+    let changed = payload_ik != user.keys.ik_pub;
+    let ik_version = if changed {
+        user.ik_version + 1
+    } else {
+        user.ik_version
+    };
+    let keys = Key::new(
+        payload.ik_pub,
+        payload.spk_pub,
+        payload.spk_signature,
+        payload.opk_pub,
+    );
+    let keys =
+        to_bson(&keys).map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, None))?;
+
+    // Legacy documents have no `ik_version` yet and read as version 1.
+    let version_filter = if user.ik_version == 1 {
+        doc! { "$in": [1, null] }
+    } else {
+        doc! { "$eq": user.ik_version }
+    };
+    let result = users
+        .update_one(
+            doc! { "uuid": user_id, "ik_version": version_filter },
+            doc! { "$set": { "keys": keys, "ik_version": ik_version } },
+        )
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
+    if result.matched_count == 0 {
+        return Err(error_response(
+            StatusCode::CONFLICT,
+            Some("Identity key was replaced concurrently"),
+        ));
+    }
+
+    if changed {
+        // The version check above makes this the only binding for
+        // `ik_version`. Should the append fail, the key is already live:
+        // friends are still told, the caller gets the error, and the first
+        // bundle served appends it again.
+        let logged = transparency::services::append(
+            &state.get_transparency_log_collection(),
+            user_id,
+            None,
+            payload_ik,
+            ik_version,
+        )
+        .await;
+
+        push_event(
+            state,
+            &user.friends,
+            EventKind::IdentityKeyChanged {
+                user_id: user_id.to_string(),
+                ik_version,
+            },
+        )
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
+
+        logged.map_err(|_| {
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Some("Transparency log error"),
+            )
+        })?;
+    }
+
+    Ok(json!({
+        "ik_version": ik_version,
+        "changed": changed,
+    }))
+}
+
+/// Safety number for the caller and one of their friends, computed from the
+/// current identity keys of both.
+pub async fn get_fingerprint(
+    users: Collection<User>,
+    user_id: &str,
+    target_id: &str,
+) -> Result<Fingerprint, (StatusCode, Json<Value>)> {
+    let target = find_user(&users, target_id).await?;
+    if !target.friends.iter().any(|id| id == user_id) {
+        return Err(error_response(
+            StatusCode::FORBIDDEN,
+            Some("Not friends with this user"),
+        ));
+    }
+    let user = find_user(&users, user_id).await?;
+
+    let local_ik = &user.keys.ik_pub;
+    let remote_ik = &target.keys.ik_pub;
+    Ok(Fingerprint {
+        safety_number: fingerprint::safety_number(user_id, local_ik, target_id, remote_ik),
+        scannable: STANDARD.encode(fingerprint::scannable(
+            user_id, local_ik, target_id, remote_ik,
+        )),
+        user_id: target.uuid,
+        ik_version: target.ik_version,
+    })
+}
+
+/// Gives the calling session's device its own key set. From then on its
+/// prekey uploads and rotations apply to that set, and senders can address it
+/// directly.
+pub async fn register_device(
+    state: &AppState,
+    user_id: &str,
+    device_id: &str,
+    payload: DeviceRegistrationPayload,
+) -> Result<DeviceInfo, (StatusCode, Json<Value>)> {
+    verify_signed_prekey(&payload.ik_pub, &payload.spk_pub, &payload.spk_signature)?;
+    validate_opks(&payload.opk_pub)?;
+
+    // Checked up front so a refused registration leaves nothing in the
+    // append-only log; the push below still guards against races.
+    let users = state.get_user_collection();
+    check_device_slot(&find_user(&users, user_id).await?, device_id)?;
+
+    // Published before the device is added, like account keys at signup.
+    let log = state.get_transparency_log_collection();
+    let log_error = |_| {
+        error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Some("Transparency log error"),
+        )
+    };
+    let ik_version = transparency::services::next_device_version(&log, user_id, device_id)
+        .await
+        .map_err(log_error)?;
+    let entry =
+        transparency::services::append(&log, user_id, Some(device_id), payload.ik_pub, ik_version)
+            .await
+            .map_err(log_error)?;
+    if entry.ik_pub != payload.ik_pub {
+        return Err(error_response(
+            StatusCode::CONFLICT,
+            Some("Device was registered concurrently"),
+        ));
+    }
+
+    let device = Device {
+        device_id: device_id.to_string(),
+        name: payload.name,
+        keys: Key::new(
+            payload.ik_pub,
+            payload.spk_pub,
+            payload.spk_signature,
+            payload.opk_pub,
+        ),
+        ik_version,
+        created_at: chrono::Utc::now().timestamp(),
+    };
+    let device_doc =
+        to_bson(&device).map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, None))?;
+
+    let result = users
+        .update_one(
+            doc! {
+                "uuid": user_id,
+                "devices.device_id": { "$ne": device_id },
+                format!("devices.{}", MAX_DEVICES - 1): { "$exists": false },
+            },
+            doc! { "$push": { "devices": device_doc } },
+        )
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
+
+    if result.matched_count == 0 {
+        check_device_slot(&find_user(&users, user_id).await?, device_id)?;
+        return Err(error_response(
+            StatusCode::CONFLICT,
+            Some("Device was registered concurrently"),
+        ));
+    }
+
+    notify_device_list_changed(state, user_id).await?;
+    Ok(DeviceInfo::from(&device))
+}
+
+/// Unregisters one of the caller's devices, signs it out and drops the
+/// messages still pending for it.
+pub async fn remove_device(
+    state: &AppState,
+    user_id: &str,
+    device_id: &str,
+) -> Result<(), (StatusCode, Json<Value>)> {
+    let result = state
+        .get_user_collection()
+        .update_one(
+            doc! { "uuid": user_id, "devices.device_id": device_id },
+            doc! { "$pull": { "devices": { "device_id": device_id } } },
+        )
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
+    if result.modified_count == 0 {
+        return Err(error_response(
+            StatusCode::NOT_FOUND,
+            Some("Device not found"),
+        ));
+    }
+
+    state
+        .get_message_collection()
+        .delete_many(doc! { "receiver": user_id, "receiver_device": device_id })
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
+    revoke_session(&state.redis, user_id, device_id)
+        .await
+        .map_err(session_store_error)?;
+    notify_device_list_changed(state, user_id).await
+}
+
+async fn notify_device_list_changed(
+    state: &AppState,
+    user_id: &str,
+) -> Result<(), (StatusCode, Json<Value>)> {
+    let user = find_user(&state.get_user_collection(), user_id).await?;
+    push_event(
+        state,
+        &user.friends,
+        EventKind::DeviceListChanged {
+            user_id: user_id.to_string(),
+        },
+    )
+    .await
+    .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))
+}
+
+/// Devices a sender has to encrypt for, visible to the user and their friends.
+pub async fn list_devices(
+    users: Collection<User>,
+    user_id: &str,
+    target_id: &str,
+) -> Result<Vec<DeviceInfo>, (StatusCode, Json<Value>)> {
+    let target = find_user(&users, target_id).await?;
+    if user_id != target_id && !target.friends.iter().any(|id| id == user_id) {
+        return Err(error_response(
+            StatusCode::FORBIDDEN,
+            Some("Not friends with this user"),
+        ));
+    }
+    Ok(target.devices.iter().map(DeviceInfo::from).collect())
+}
@@ -0,0 +1,161 @@
+use axum::{http::StatusCode, Json};
+use mongodb::bson::{doc, Document};
+use serde_json::Value;
+
+use crate::{
+    keys::xeddsa,
+    user::models::{Key, OneTimePreKeyPublic, User},
+    utils::error::error_response,
+};
+
+/// Upper bound on the one-time prekeys stored per user.
+pub const MAX_OPK_COUNT: usize = 100;
+/// Below this many one-time prekeys the client is asked to replenish.
+pub const OPK_LOW_WATERMARK: usize = 10;
+/// Upper bound on the devices with their own key set per user.
+pub const MAX_DEVICES: usize = 10;
+
+/// Which key set of a user an operation applies to: the account keys given at
+/// registration, or those of one registered device.
+#[derive(Debug, Clone)]
+pub enum KeyTarget {
+    Account,
+    Device(String),
+}
+
+impl KeyTarget {
+    /// The key set used by a session: its device's own keys once that device
+    /// is registered, the account keys otherwise.
+    pub fn for_session(user: &User, device_id: &str) -> Self {
+        match user.device(device_id) {
+            Some(_) => Self::Device(device_id.to_string()),
+            None => Self::Account,
+        }
+    }
+
+    pub fn device_id(&self) -> Option<&str> {
+        match self {
+            Self::Account => None,
+            Self::Device(device_id) => Some(device_id),
+        }
+    }
+
+    /// Filter on the owner of the key set, with `conditions` written against
+    /// fields of `Key` (e.g. `spk_pub`).
+    pub fn filter(&self, user_id: &str, conditions: Document) -> Document {
+        match self {
+            Self::Account => {
+                let mut filter = doc! { "uuid": user_id };
+                for (field, condition) in conditions {
+                    filter.insert(format!("keys.{field}"), condition);
+                }
+                filter
+            }
+            Self::Device(device_id) => {
+                let mut element = doc! { "device_id": device_id };
+                for (field, condition) in conditions {
+                    element.insert(format!("keys.{field}"), condition);
+                }
+                doc! { "uuid": user_id, "devices": { "$elemMatch": element } }
+            }
+        }
+    }
+
+    /// Update path of a `Key` field. The device form relies on the positional
+    /// operator, so the filter must come from [`KeyTarget::filter`].
+    pub fn path(&self, field: &str) -> String {
+        match self {
+            Self::Account => format!("keys.{field}"),
+            Self::Device(_) => format!("devices.$.keys.{field}"),
+        }
+    }
+
+    pub fn keys<'a>(&self, user: &'a User) -> Option<&'a Key> {
+        match self {
+            Self::Account => Some(&user.keys),
+            Self::Device(device_id) => user.device(device_id).map(|device| &device.keys),
+        }
+    }
+
+    pub fn into_keys(self, user: User) -> Option<Key> {
+        match self {
+            Self::Account => Some(user.keys),
+            Self::Device(device_id) => user
+                .devices
+                .into_iter()
+                .find(|device| device.device_id == device_id)
+                .map(|device| device.keys),
+        }
+    }
+}
+
+pub fn is_opk_pool_low(keys: &Key) -> bool {
+    keys.opk_pub.len() < OPK_LOW_WATERMARK
+}
+
+/// Rejects a signed prekey whose signature was not produced by `ik_pub`.
+pub fn verify_signed_prekey(
+    ik_pub: &[u8; 32],
+    spk_pub: &[u8; 32],
+    spk_signature: &[u8],
+) -> Result<(), (StatusCode, Json<Value>)> {
+    if spk_signature.len() != xeddsa::SIGNATURE_LENGTH {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            Some("Signed prekey signature must be 64 bytes"),
+        ));
+    }
+    if !xeddsa::verify(ik_pub, spk_pub, spk_signature) {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            Some("Invalid signed prekey signature"),
+        ));
+    }
+    Ok(())
+}
+
+/// Refuses a device registration when `device_id` already has a key set or
+/// the user reached [`MAX_DEVICES`].
+pub fn check_device_slot(user: &User, device_id: &str) -> Result<(), (StatusCode, Json<Value>)> {
+    if user.device(device_id).is_some() {
+        return Err(error_response(
+            StatusCode::CONFLICT,
+            Some("Device already registered"),
+        ));
+    }
+    if user.devices.len() >= MAX_DEVICES {
+        return Err(error_response(
+            StatusCode::CONFLICT,
+            Some("Too many devices"),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks a batch of one-time prekeys before it is stored: bounded in size,
+/// with non-empty ids that are unique within the batch.
+pub fn validate_opks(opks: &[OneTimePreKeyPublic]) -> Result<(), (StatusCode, Json<Value>)> {
+    if opks.len() > MAX_OPK_COUNT {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            Some("Too many one-time prekeys"),
+        ));
+    }
+
+    let mut ids: Vec<&str> = opks.iter().map(|opk| opk.uuid.as_str()).collect();
+    if ids.iter().any(|id| id.is_empty()) {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            Some("One-time prekey id cannot be empty"),
+        ));
+    }
+    ids.sort_unstable();
+    ids.dedup();
+    if ids.len() != opks.len() {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            Some("Duplicate one-time prekey id"),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use ed25519_dalek::{Signature, VerifyingKey};
+
+pub const SIGNATURE_LENGTH: usize = 64;
+
+/// Verifies an XEdDSA signature made with the private half of an X25519 key,
+/// as used for signed prekeys in X3DH.
+///
+/// The Edwards sign bit is read from the top bit of the signature, where
+/// libsignal stores it; signers following the spec leave it at zero.
+pub fn verify(public_key: &[u8; 32], message: &[u8], signature: &[u8]) -> bool {
+    let Ok(mut signature) = <[u8; SIGNATURE_LENGTH]>::try_from(signature) else {
+        return false;
+    };
+    let sign_bit = (signature[SIGNATURE_LENGTH - 1] & 0x80) >> 7;
+    signature[SIGNATURE_LENGTH - 1] &= 0x7f;
+
+    let Some(edwards) = MontgomeryPoint(*public_key).to_edwards(sign_bit) else {
+        return false;
+    };
+    let Ok(verifying_key) = VerifyingKey::from_bytes(&edwards.compress().to_bytes()) else {
+        return false;
+    };
+    verifying_key
+        .verify_strict(message, &Signature::from_bytes(&signature))
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `testSignature` of libsignal's `CurveTest`: Alice's identity key signs
+    /// her serialized ephemeral key.
+    const IDENTITY_PUBLIC: [u8; 32] = [
+        0xab, 0x7e, 0x71, 0x7d, 0x4a, 0x16, 0x3b, 0x7d, 0x9a, 0x1d, 0x80, 0x71, 0xdf, 0xe9, 0xdc,
+        0xf8, 0xcd, 0xcd, 0x1c, 0xea, 0x33, 0x39, 0xb6, 0x35, 0x6b, 0xe8, 0x4d, 0x88, 0x7e, 0x32,
+        0x2c, 0x64,
+    ];
+    const EPHEMERAL_PUBLIC: [u8; 33] = [
+        0x05, 0xed, 0xce, 0x9d, 0x9c, 0x41, 0x5c, 0xa7, 0x8c, 0xb7, 0x25, 0x2e, 0x72, 0xc2, 0xc4,
+        0xa5, 0x54, 0xd3, 0xeb, 0x29, 0x48, 0x5a, 0x0e, 0x1d, 0x50, 0x31, 0x18, 0xd1, 0xa8, 0x2d,
+        0x99, 0xfb, 0x4a,
+    ];
+    const SIGNATURE: [u8; SIGNATURE_LENGTH] = [
+        0x5d, 0xe8, 0x8c, 0xa9, 0xa8, 0x9b, 0x4a, 0x11, 0x5d, 0xa7, 0x91, 0x09, 0xc6, 0x7c, 0x9c,
+        0x74, 0x64, 0xa3, 0xe4, 0x18, 0x02, 0x74, 0xf1, 0xcb, 0x8c, 0x63, 0xc2, 0x98, 0x4e, 0x28,
+        0x6d, 0xfb, 0xed, 0xe8, 0x2d, 0xeb, 0x9d, 0xcd, 0x9f, 0xae, 0x0b, 0xfb, 0xb8, 0x21, 0x56,
+        0x9b, 0x3d, 0x90, 0x01, 0xbd, 0x81, 0x30, 0xcd, 0x11, 0xd4, 0x86, 0xce, 0xf0, 0x47, 0xbd,
+        0x60, 0xb8, 0x6e, 0x88,
+    ];
+
+    #[test]
+    fn accepts_libsignal_signature() {
+        // The top bit is set: the Edwards key has a negative x coordinate.
+        assert_eq!(SIGNATURE[SIGNATURE_LENGTH - 1] & 0x80, 0x80);
+        assert!(verify(&IDENTITY_PUBLIC, &EPHEMERAL_PUBLIC, &SIGNATURE));
+    }
+
+    #[test]
+    fn rejects_flipped_sign_bit() {
+        let mut signature = SIGNATURE;
+        signature[SIGNATURE_LENGTH - 1] ^= 0x80;
+        assert!(!verify(&IDENTITY_PUBLIC, &EPHEMERAL_PUBLIC, &signature));
+    }
+
+    #[test]
+    fn rejects_wrong_key_or_message() {
+        let other_key: [u8; 32] = EPHEMERAL_PUBLIC[1..].try_into().unwrap();
+        assert!(!verify(&other_key, &EPHEMERAL_PUBLIC, &SIGNATURE));
+
+        let mut message = EPHEMERAL_PUBLIC;
+        message[1] ^= 0x01;
+        assert!(!verify(&IDENTITY_PUBLIC, &message, &SIGNATURE));
+
+        assert!(!verify(
+            &IDENTITY_PUBLIC,
+            &EPHEMERAL_PUBLIC,
+            &SIGNATURE[..63]
+        ));
+    }
+}
@@ -1,7 +1,11 @@
 pub mod auth;
+pub mod event;
+pub mod keys;
 pub mod message;
+pub mod realtime;
 pub mod routes;
 pub mod state;
 pub mod system;
+pub mod transparency;
 pub mod user;
 pub mod utils;
@@ -1,9 +1,17 @@
-use axum::Router;
+use axum::{middleware, Router};
 use lucchat_api::{
+    auth::{keyring::KeyRing, password::PasswordPolicy},
+    event,
+    keys::models::KeyPolicy,
+    message,
+    realtime::hub::Hub,
     routes::{
-        auth::auth_routes, message::message_routes, system::system_routes, user::user_routes,
+        auth::auth_routes, events::events_routes, keys::keys_routes, message::message_routes,
+        system::system_routes, user::user_routes, ws::ws_routes,
     },
     state::AppState,
+    transparency,
+    utils::request::{resolve_client_addr, ProxyPolicy},
 };
 use shuttle_runtime::SecretStore;
 
@@ -13,24 +21,67 @@ async fn main(#[shuttle_runtime::Secrets] secret_store: SecretStore) -> shuttle_
     let redis_uri = secret_store.get("REDIS_URI").expect("missing REDIS_URI");
     let mongo = mongodb::Client::with_uri_str(&mongo_uri).await.unwrap();
     let redis = redis::Client::open(redis_uri).expect("invalid redis URI");
+    let keyring = KeyRing::from_secret_store(&secret_store).expect("invalid JWT keyring");
+    let password_policy =
+        PasswordPolicy::from_secret_store(&secret_store).expect("invalid password policy");
+    let key_policy = KeyPolicy::from_secret_store(&secret_store).expect("invalid key policy");
+    let proxy_policy = ProxyPolicy::from_secret_store(&secret_store).expect("invalid proxy policy");
+
+    let hub = Hub::with_redis(redis.clone());
 
     let app_state = AppState {
         mongo,
         secret_store,
         redis,
+        keyring,
+        password_policy,
+        key_policy,
+        proxy_policy,
+        hub,
+        transparency_cache: Default::default(),
         started_at: std::time::Instant::now(),
     };
 
+    let messages = app_state.get_message_collection();
+    message::services::create_indexes(&messages)
+        .await
+        .expect("failed to create message indexes");
+    let database = app_state.mongo.database("lucchat");
+    message::services::migrate_embedded_messages(
+        &database.collection("users"),
+        &messages,
+        &database.collection("quarantined_messages"),
+        &database.collection("migrations"),
+    )
+    .await
+    .expect("failed to migrate embedded messages");
+    event::services::create_indexes(&app_state.get_event_collection())
+        .await
+        .expect("failed to create event indexes");
+    transparency::services::create_indexes(&app_state.get_transparency_log_collection())
+        .await
+        .expect("failed to create transparency log indexes");
+
     let user_routes = user_routes(app_state.clone());
     let auth_routes = auth_routes(app_state.clone());
     let message_routes = message_routes(app_state.clone());
+    let keys_routes = keys_routes(app_state.clone());
     let system_routes = system_routes();
+    let ws_routes = ws_routes();
+    let events_routes = events_routes();
 
     let app = Router::new()
         .merge(auth_routes)
         .merge(user_routes)
         .merge(message_routes)
+        .merge(keys_routes)
         .merge(system_routes)
+        .merge(ws_routes)
+        .merge(events_routes)
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            resolve_client_addr,
+        ))
         .with_state(app_state);
 
     Ok(app.into())
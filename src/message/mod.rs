@@ -1,3 +1,4 @@
 pub mod models;
 pub mod payload;
+pub mod receipts;
 pub mod services;
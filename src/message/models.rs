@@ -6,12 +6,23 @@ pub struct Message {
     pub uuid: String,
     pub sender: String,
     pub receiver: String,
+    /// Device the ciphertext was encrypted on, when it has its own key set.
+    #[serde(default)]
+    pub sender_device: Option<String>,
+    /// Recipient device the ciphertext is for; `None` targets the account keys.
+    #[serde(default)]
+    pub receiver_device: Option<String>,
     pub nonce: [u8; 12],
     pub ciphertext: Vec<u8>,
     pub ratchet_pub: [u8; 32], // DH public key used in ratchet step
     pub message_index: u32,    // Index in chain key (CKs.index)
     pub opk_used: Option<OneTimePreKeyPublic>,
     pub ek_used: Option<[u8; 32]>,
+    /// Receiver's signed prekey the initial message was built against.
+    #[serde(default)]
+    pub spk_id: Option<String>,
+    /// Set by the server when the message is accepted.
+    #[serde(default)]
     pub created_at: i64,
 }
 
@@ -21,6 +32,18 @@ impl Message {
             uuid: self.uuid.clone(),
             sender: self.sender.clone(),
             receiver: self.receiver.clone(),
+            sender_device: self.sender_device.clone(),
+            receiver_device: self.receiver_device.clone(),
+            created_at: self.created_at,
+            size: self.ciphertext.len(),
+            has_prekey_message: self.ek_used.is_some(),
         }
     }
 }
+
+/// Oldest pending messages of a device, left in place until acked.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageBatch {
+    pub messages: Vec<Message>,
+    pub has_more: bool,
+}
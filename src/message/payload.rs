@@ -1 +1,11 @@
+use serde::{Deserialize, Serialize};
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FetchPayload {
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AckPayload {
+    pub uuids: Vec<String>,
+}
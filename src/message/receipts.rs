@@ -0,0 +1,42 @@
+use redis::{AsyncCommands, Client};
+
+/// How long a delivered message can still be marked as read.
+pub const READ_RECEIPT_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+fn delivered_key(receiver: &str, message_id: &str) -> String {
+    format!("delivered:{receiver}:{message_id}")
+}
+
+/// Remembers who sent the messages the receiver just fetched, since the
+/// messages themselves are gone once delivered. `deliveries` holds
+/// `(message_id, sender)` pairs, written in a single pipeline.
+pub async fn record_deliveries(
+    redis: &Client,
+    receiver: &str,
+    deliveries: &[(&str, &str)],
+) -> redis::RedisResult<()> {
+    if deliveries.is_empty() {
+        return Ok(());
+    }
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    let mut pipe = redis::pipe();
+    for (message_id, sender) in deliveries {
+        pipe.set_ex(
+            delivered_key(receiver, message_id),
+            sender,
+            READ_RECEIPT_TTL_SECS,
+        )
+        .ignore();
+    }
+    pipe.query_async(&mut conn).await
+}
+
+/// Sender of a delivered message, consumed so a message is only read once.
+pub async fn take_delivery(
+    redis: &Client,
+    receiver: &str,
+    message_id: &str,
+) -> redis::RedisResult<Option<String>> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    conn.get_del(delivered_key(receiver, message_id)).await
+}
@@ -1,17 +1,147 @@
+use crate::event::{
+    models::EventKind,
+    services::{push_event, push_events},
+};
+use crate::message::models::{Message, MessageBatch};
+use crate::message::receipts::{record_deliveries, take_delivery};
+use crate::state::AppState;
 use crate::user::utils::find_user;
-use crate::utils::error::error_response;
-use crate::{message::models::Message, user::models::User};
+use crate::utils::error::{error_response, is_duplicate_key, session_store_error};
 use axum::{http::StatusCode, Json};
+use futures::stream::TryStreamExt;
 use mongodb::{
-    bson::{doc, to_document},
-    Collection,
+    bson::{doc, from_bson, Bson, Document},
+    options::IndexOptions,
+    Collection, IndexModel,
 };
 use serde_json::Value;
 
+/// Marks the move of embedded `User.unread_messages` into the `messages`
+/// collection as done, in the `migrations` collection.
+const EMBEDDED_MESSAGES_MIGRATION: &str = "embedded_unread_messages";
+
+pub const DEFAULT_FETCH_SIZE: i64 = 100;
+pub const MAX_FETCH_SIZE: i64 = 500;
+/// Upper bound on the uuids accepted by a single ack.
+pub const MAX_ACK_SIZE: usize = 500;
+/// After this long, the claim of an ack that never finished is ignored.
+const ACK_CLAIM_TIMEOUT_SECS: i64 = 60;
+
+/// Matches messages no pending ack has claimed, so two devices acking or
+/// reading the same message cannot both hand it over.
+fn unclaimed(now: i64) -> Document {
+    doc! {
+        "$or": [
+            { "ack": { "$exists": false } },
+            { "ack.at": { "$lt": now - ACK_CLAIM_TIMEOUT_SECS } },
+        ]
+    }
+}
+
+pub async fn create_indexes(messages: &Collection<Message>) -> mongodb::error::Result<()> {
+    messages
+        .create_indexes([
+            IndexModel::builder()
+                .keys(doc! { "receiver": 1, "created_at": 1 })
+                .build(),
+            IndexModel::builder()
+                .keys(doc! { "uuid": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+        ])
+        .await?;
+    Ok(())
+}
+
+/// Moves messages still embedded in user documents into the `messages`
+/// collection. Safe to rerun: already copied messages are skipped through the
+/// unique `uuid` index, and the array is only dropped once copied.
+///
+/// Entries that no longer decode as a [`Message`] are kept as is in
+/// `quarantine`, with the owner and the decoding error, rather than dropped.
+/// The marker records how many messages were copied and quarantined.
+pub async fn migrate_embedded_messages(
+    users: &Collection<Document>,
+    messages: &Collection<Message>,
+    quarantine: &Collection<Document>,
+    migrations: &Collection<Document>,
+) -> mongodb::error::Result<()> {
+    if migrations
+        .find_one(doc! { "_id": EMBEDDED_MESSAGES_MIGRATION })
+        .await?
+        .is_some()
+    {
+        return Ok(());
+    }
+
+    let mut cursor = users
+        .find(doc! { "unread_messages": { "$exists": true } })
+        .projection(doc! { "uuid": 1, "unread_messages": 1 })
+        .await?;
+    let (mut copied, mut quarantined) = (0_i64, 0_i64);
+    while let Some(user) = cursor.try_next().await? {
+        let user_id = user.get("uuid").cloned().unwrap_or(Bson::Null);
+        let embedded = user
+            .get_array("unread_messages")
+            .cloned()
+            .unwrap_or_default();
+        for (position, raw) in embedded.into_iter().enumerate() {
+            let message = match from_bson::<Message>(raw.clone()) {
+                Ok(message) => message,
+                Err(e) => {
+                    // Keyed by position so a rerun does not quarantine twice.
+                    let id = format!("{}:{position}", user_id.as_str().unwrap_or_default());
+                    quarantine
+                        .replace_one(
+                            doc! { "_id": &id },
+                            doc! {
+                                "_id": &id,
+                                "user_id": user_id.clone(),
+                                "message": raw,
+                                "error": e.to_string(),
+                                "quarantined_at": chrono::Utc::now().timestamp(),
+                            },
+                        )
+                        .upsert(true)
+                        .await?;
+                    quarantined += 1;
+                    continue;
+                }
+            };
+            match messages.insert_one(&message).await {
+                Ok(_) => copied += 1,
+                Err(e) if is_duplicate_key(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        users
+            .update_one(
+                doc! { "_id": user.get("_id").cloned().unwrap_or(Bson::Null) },
+                doc! { "$unset": { "unread_messages": "" } },
+            )
+            .await?;
+    }
+
+    // Another instance may have finished the same migration concurrently.
+    match migrations
+        .insert_one(doc! {
+            "_id": EMBEDDED_MESSAGES_MIGRATION,
+            "completed_at": chrono::Utc::now().timestamp(),
+            "copied": copied,
+            "quarantined": quarantined,
+        })
+        .await
+    {
+        Err(e) if !is_duplicate_key(&e) => Err(e),
+        _ => Ok(()),
+    }
+}
+
 pub async fn send_message(
-    users: Collection<User>,
+    state: &AppState,
     user_id: &str,
-    message: Message,
+    device_id: &str,
+    mut message: Message,
 ) -> Result<(), (StatusCode, Json<Value>)> {
     if user_id != message.sender {
         return Err(error_response(
@@ -27,65 +157,264 @@ pub async fn send_message(
         ));
     }
 
-    if (find_user(&users, message.receiver.as_str()).await).is_err() {
+    let Ok(receiver) = find_user(&state.get_user_collection(), message.receiver.as_str()).await
+    else {
         return Err(error_response(
             StatusCode::NOT_FOUND,
             Some("Receiver user does not exist"),
         ));
+    };
+
+    if message
+        .sender_device
+        .as_deref()
+        .is_some_and(|sender_device| sender_device != device_id)
+    {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            Some("The sender device and the session device must be the same"),
+        ));
     }
 
-    let msg_doc = to_document(&message).map_err(|e| {
-        error_response(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Some(&format!("Failed to convert message to document: {}", e)),
-        )
-    })?;
-    users
-        .update_one(
-            doc! { "uuid": &message.receiver },
-            doc! { "$push": { "unread_messages": msg_doc } },
-        )
+    let receiver_keys = match &message.receiver_device {
+        Some(receiver_device) => match receiver.device(receiver_device) {
+            Some(device) => &device.keys,
+            None => {
+                return Err(error_response(
+                    StatusCode::NOT_FOUND,
+                    Some("Receiver device does not exist"),
+                ))
+            }
+        },
+        None => &receiver.keys,
+    };
+
+    if let Some(spk_id) = &message.spk_id {
+        if !receiver_keys.accepts_spk(spk_id, chrono::Utc::now().timestamp()) {
+            return Err(error_response(
+                StatusCode::CONFLICT,
+                Some("Unknown or expired signed prekey"),
+            ));
+        }
+    }
+
+    // Listings and fetches are ordered by `created_at`: a client supplied one
+    // could slip a message behind a cursor the receiver already holds.
+    message.created_at = chrono::Utc::now().timestamp();
+    state
+        .get_message_collection()
+        .insert_one(&message)
         .await
         .map_err(|e| {
-            error_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Some(&format!("Failed to update unread messages: {}", e)),
-            )
+            if is_duplicate_key(&e) {
+                error_response(StatusCode::CONFLICT, Some("Message id already in use"))
+            } else {
+                error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Some(&format!("Failed to store message: {}", e)),
+                )
+            }
         })?;
+    state.hub.push_message(&message).await;
+    let _ = push_event(
+        state,
+        std::slice::from_ref(&message.receiver),
+        EventKind::MessageReceived {
+            message: message.message_info(),
+        },
+    )
+    .await;
     Ok(())
 }
 
+/// Hands a pending message addressed to the calling device, or to the account
+/// keys, and drops it server side. The sender gets a delivery receipt, and the
+/// delivery is remembered so the receiver can later mark the message as read.
 pub async fn read_message(
-    users: Collection<User>,
+    state: &AppState,
     user_id: &str,
+    device_id: &str,
     message_id: &str,
 ) -> Result<Message, (StatusCode, Json<Value>)> {
-    let update_result = users
-        .find_one_and_update(
-            doc! { "uuid": user_id, "unread_messages.uuid": message_id },
-            doc! { "$pull": { "unread_messages": { "uuid": message_id } } },
-        )
+    let message = state
+        .get_message_collection()
+        .find_one_and_delete(doc! {
+            "uuid": message_id,
+            "receiver": user_id,
+            "receiver_device": { "$in": [device_id, null] },
+            "$and": [unclaimed(chrono::Utc::now().timestamp())],
+        })
         .await
         .map_err(|e| {
             error_response(
                 StatusCode::INTERNAL_SERVER_ERROR,
-                Some(&format!("Failed to update user: {}", e)),
+                Some(&format!("Failed to read message: {}", e)),
+            )
+        })?
+        .ok_or_else(|| {
+            error_response(
+                StatusCode::NOT_FOUND,
+                Some("Message not found in unread messages"),
             )
         })?;
 
-    let user = update_result
-        .ok_or_else(|| error_response(StatusCode::NOT_FOUND, Some("User or message not found")))?;
+    send_delivery_receipts(state, user_id, device_id, std::slice::from_ref(&message)).await;
+    Ok(message)
+}
+
+/// Tells the senders their messages reached `device_id`, and remembers each
+/// delivery so the receiver can later mark the message as read. Best effort:
+/// the messages are already handed over.
+async fn send_delivery_receipts(
+    state: &AppState,
+    user_id: &str,
+    device_id: &str,
+    delivered: &[Message],
+) {
+    let deliveries: Vec<(&str, &str)> = delivered
+        .iter()
+        .map(|message| (message.uuid.as_str(), message.sender.as_str()))
+        .collect();
+    let _ = record_deliveries(&state.redis, user_id, &deliveries).await;
+    let _ = push_events(
+        state,
+        delivered
+            .iter()
+            .map(|message| {
+                (
+                    message.sender.clone(),
+                    EventKind::MessageDelivered {
+                        message_id: message.uuid.clone(),
+                        user_id: user_id.to_string(),
+                        device_id: device_id.to_string(),
+                    },
+                )
+            })
+            .collect(),
+    )
+    .await;
+}
+
+/// Up to `limit` of the oldest pending messages of the device, in full. They
+/// stay queued until acked, so a client that crashes before storing them gets
+/// them again.
+pub async fn fetch_messages(
+    messages: Collection<Message>,
+    user_id: &str,
+    device_id: &str,
+    limit: Option<i64>,
+) -> Result<MessageBatch, (StatusCode, Json<Value>)> {
+    let limit = limit.unwrap_or(DEFAULT_FETCH_SIZE).clamp(1, MAX_FETCH_SIZE);
+
+    // One extra message tells whether more are pending.
+    let mut batch: Vec<Message> = messages
+        .find(doc! {
+            "receiver": user_id,
+            "receiver_device": { "$in": [device_id, null] },
+        })
+        .sort(doc! { "created_at": 1, "uuid": 1 })
+        .limit(limit + 1)
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?
+        .try_collect()
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
+
+    let has_more = batch.len() as i64 > limit;
+    batch.truncate(limit as usize);
+    Ok(MessageBatch {
+        messages: batch,
+        has_more,
+    })
+}
+
+/// Drops the given messages once the device stored them and sends the
+/// delivery receipts. Returns the uuids actually removed; unknown ones, or
+/// ones acked already, are ignored.
+pub async fn ack_messages(
+    state: &AppState,
+    user_id: &str,
+    device_id: &str,
+    uuids: Vec<String>,
+) -> Result<Vec<String>, (StatusCode, Json<Value>)> {
+    if uuids.len() > MAX_ACK_SIZE {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            Some(&format!("At most {MAX_ACK_SIZE} messages per ack")),
+        ));
+    }
+    if uuids.is_empty() {
+        return Ok(Vec::new());
+    }
 
-    let message = user
-        .unread_messages
-        .into_iter()
-        .find(|msg| msg.uuid == message_id)
+    // Claiming is atomic per message: only the ack that tags a message with
+    // its token deletes it and sends its receipt.
+    let messages = state.get_message_collection();
+    let token = uuid::Uuid::new_v4().to_string();
+    let now = chrono::Utc::now().timestamp();
+    let claimed = messages
+        .clone_with_type::<Document>()
+        .update_many(
+            doc! {
+                "uuid": { "$in": uuids },
+                "receiver": user_id,
+                "receiver_device": { "$in": [device_id, null] },
+                "$and": [unclaimed(now)],
+            },
+            doc! { "$set": { "ack": { "token": &token, "at": now } } },
+        )
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
+    if claimed.modified_count == 0 {
+        return Ok(Vec::new());
+    }
+
+    let acked: Vec<Message> = messages
+        .find(doc! { "ack.token": &token })
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?
+        .try_collect()
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
+    messages
+        .delete_many(doc! { "ack.token": &token })
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
+
+    send_delivery_receipts(state, user_id, device_id, &acked).await;
+    Ok(acked.into_iter().map(|message| message.uuid).collect())
+}
+
+/// Marks a delivered message as read. The sender only gets a read receipt
+/// when the reader did not turn them off.
+pub async fn mark_read(
+    state: &AppState,
+    user_id: &str,
+    message_id: &str,
+) -> Result<(), (StatusCode, Json<Value>)> {
+    let sender = take_delivery(&state.redis, user_id, message_id)
+        .await
+        .map_err(session_store_error)?
         .ok_or_else(|| {
             error_response(
                 StatusCode::NOT_FOUND,
-                Some("Message not found in unread messages"),
+                Some("Message not found in delivered messages"),
             )
         })?;
 
-    Ok(message)
+    let reader = find_user(&state.get_user_collection(), user_id).await?;
+    if !reader.read_receipts {
+        return Ok(());
+    }
+
+    push_event(
+        state,
+        &[sender],
+        EventKind::MessageRead {
+            message_id: message_id.to_string(),
+            user_id: user_id.to_string(),
+        },
+    )
+    .await
+    .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))
 }
@@ -0,0 +1,324 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use futures::StreamExt;
+use redis::Client;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    mpsc::{self, error::TrySendError, unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+
+use crate::{event::models::Event, message::models::Message};
+
+/// How long `subscribe` waits for Redis to confirm the channel subscription.
+const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Pause before reconnecting the pub/sub connection after it dropped.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Pushes a connection may have waiting before it is dropped as too slow.
+pub const CONNECTION_BUFFER: usize = 256;
+
+const CHANNEL_PREFIX: &str = "inbox:";
+
+fn channel(user_id: &str) -> String {
+    format!("{CHANNEL_PREFIX}{user_id}")
+}
+
+/// What the hub hands to a live connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Push {
+    Message(Message),
+    Event(Event),
+}
+
+/// Payload published on a user's channel.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    device_id: Option<String>,
+    push: Push,
+}
+
+enum Command {
+    Subscribe(String, oneshot::Sender<()>),
+    Unsubscribe(String),
+}
+
+struct Connection {
+    id: u64,
+    device_id: String,
+    sender: mpsc::Sender<Push>,
+}
+
+/// Live connections of this instance, by user. Pushing is best effort:
+/// whatever is pushed is also stored, and a connection replays its backlog
+/// when it opens. A connection that lets [`CONNECTION_BUFFER`] pushes pile up
+/// is dropped, ending its [`Subscription`], rather than buffering without
+/// bound.
+///
+/// Built with [`Hub::with_redis`], pushes go through a per-user Redis channel
+/// that every instance holding a connection of that user subscribes to, so a
+/// message accepted on one node reaches sockets opened on another.
+#[derive(Clone, Default)]
+pub struct Hub {
+    next_id: Arc<AtomicU64>,
+    connections: Arc<RwLock<HashMap<String, Vec<Connection>>>>,
+    fanout: Option<Fanout>,
+}
+
+#[derive(Clone)]
+struct Fanout {
+    redis: Client,
+    commands: UnboundedSender<Command>,
+}
+
+impl Hub {
+    /// Spawns the pub/sub task, so it must be called within a Tokio runtime.
+    /// The task stops once every clone of the hub is dropped.
+    pub fn with_redis(redis: Client) -> Self {
+        let (commands, receiver) = unbounded_channel();
+        let hub = Self {
+            fanout: Some(Fanout {
+                redis: redis.clone(),
+                commands,
+            }),
+            ..Self::default()
+        };
+        let local = Self {
+            next_id: hub.next_id.clone(),
+            connections: hub.connections.clone(),
+            fanout: None,
+        };
+        tokio::spawn(run_fanout(local, redis, receiver));
+        hub
+    }
+
+    /// Registers a connection of `device_id`, until the returned
+    /// [`Subscription`] is dropped. With Redis fan-out, returns once the
+    /// user's channel is subscribed (or the attempt timed out).
+    pub async fn subscribe(&self, user_id: &str, device_id: &str) -> Subscription {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel(CONNECTION_BUFFER);
+        let subscribed = {
+            let mut connections = self.connections.write().unwrap();
+            let user_connections = connections.entry(user_id.to_string()).or_default();
+            user_connections.push(Connection {
+                id,
+                device_id: device_id.to_string(),
+                sender,
+            });
+            // Commands are queued under the lock so they reach Redis in the
+            // same order as the local changes.
+            match &self.fanout {
+                Some(fanout) if user_connections.len() == 1 => {
+                    let (ack, subscribed) = oneshot::channel();
+                    let _ = fanout
+                        .commands
+                        .send(Command::Subscribe(user_id.to_string(), ack));
+                    Some(subscribed)
+                }
+                _ => None,
+            }
+        };
+        if let Some(subscribed) = subscribed {
+            let _ = tokio::time::timeout(SUBSCRIBE_TIMEOUT, subscribed).await;
+        }
+
+        Subscription {
+            hub: self.clone(),
+            user_id: user_id.to_string(),
+            id,
+            receiver,
+        }
+    }
+
+    fn unsubscribe(&self, user_id: &str, connection_id: u64) {
+        let mut connections = self.connections.write().unwrap();
+        if let Some(user_connections) = connections.get_mut(user_id) {
+            user_connections.retain(|connection| connection.id != connection_id);
+            if user_connections.is_empty() {
+                connections.remove(user_id);
+                if let Some(fanout) = &self.fanout {
+                    let _ = fanout
+                        .commands
+                        .send(Command::Unsubscribe(user_id.to_string()));
+                }
+            }
+        }
+    }
+
+    fn connected_users(&self) -> Vec<String> {
+        self.connections.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Hands `push` to the connections of `user_id` on this instance only,
+    /// dropping those whose buffer is full.
+    fn dispatch(&self, user_id: &str, device_id: Option<&str>, push: Push) {
+        let lagging: Vec<u64> = {
+            let connections = self.connections.read().unwrap();
+            let Some(user_connections) = connections.get(user_id) else {
+                return;
+            };
+            user_connections
+                .iter()
+                .filter(|connection| device_id.is_none_or(|id| id == connection.device_id))
+                .filter(|connection| {
+                    matches!(
+                        connection.sender.try_send(push.clone()),
+                        Err(TrySendError::Full(_))
+                    )
+                })
+                .map(|connection| connection.id)
+                .collect()
+        };
+        if lagging.is_empty() {
+            return;
+        }
+        // Dropping the sender ends the subscription. The user's entry is left
+        // for `unsubscribe`, which releases the Redis channel once the
+        // subscription itself is dropped.
+        let mut connections = self.connections.write().unwrap();
+        if let Some(user_connections) = connections.get_mut(user_id) {
+            user_connections.retain(|connection| !lagging.contains(&connection.id));
+        }
+    }
+
+    /// Sends `push` to the connections of `user_id` on every instance,
+    /// restricted to one device when `device_id` is set. Falls back to local
+    /// connections when Redis cannot be reached.
+    pub async fn push(&self, user_id: &str, device_id: Option<&str>, push: Push) {
+        self.push_many(vec![(
+            user_id.to_string(),
+            device_id.map(str::to_string),
+            push,
+        )])
+        .await;
+    }
+
+    /// Same as [`Hub::push`] for a batch of `(user_id, device_id, push)`,
+    /// published in a single Redis pipeline.
+    pub async fn push_many(&self, pushes: Vec<(String, Option<String>, Push)>) {
+        if let Some(fanout) = &self.fanout {
+            let envelopes: Vec<(String, Envelope)> = pushes
+                .into_iter()
+                .map(|(user_id, device_id, push)| (user_id, Envelope { device_id, push }))
+                .collect();
+            if publish(&fanout.redis, &envelopes).await.is_ok() {
+                return;
+            }
+            for (user_id, envelope) in envelopes {
+                self.dispatch(&user_id, envelope.device_id.as_deref(), envelope.push);
+            }
+            return;
+        }
+        for (user_id, device_id, push) in pushes {
+            self.dispatch(&user_id, device_id.as_deref(), push);
+        }
+    }
+
+    pub async fn push_message(&self, message: &Message) {
+        self.push(
+            &message.receiver,
+            message.receiver_device.as_deref(),
+            Push::Message(message.clone()),
+        )
+        .await;
+    }
+}
+
+async fn publish(redis: &Client, envelopes: &[(String, Envelope)]) -> redis::RedisResult<()> {
+    if envelopes.is_empty() {
+        return Ok(());
+    }
+    let mut conn = redis.get_multiplexed_async_connection().await?;
+    let mut pipe = redis::pipe();
+    for (user_id, envelope) in envelopes {
+        let payload = serde_json::to_string(envelope).expect("pushes always serialize");
+        pipe.publish(channel(user_id), payload).ignore();
+    }
+    pipe.query_async(&mut conn).await
+}
+
+/// Owns the pub/sub connection: applies subscription changes and dispatches
+/// what other instances publish to the local connections. After a dropped
+/// connection it reconnects and resubscribes every user connected here.
+async fn run_fanout(local: Hub, redis: Client, mut commands: UnboundedReceiver<Command>) {
+    while !commands.is_closed() {
+        let Ok(pubsub) = redis.get_async_pubsub().await else {
+            tokio::time::sleep(RECONNECT_DELAY).await;
+            continue;
+        };
+        let (mut sink, mut stream) = pubsub.split();
+
+        let channels: Vec<String> = local
+            .connected_users()
+            .iter()
+            .map(|user_id| channel(user_id))
+            .collect();
+        if !channels.is_empty() && sink.subscribe(&channels).await.is_err() {
+            tokio::time::sleep(RECONNECT_DELAY).await;
+            continue;
+        }
+
+        loop {
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(Command::Subscribe(user_id, ack)) => {
+                        if sink.subscribe(channel(&user_id)).await.is_err() {
+                            break;
+                        }
+                        let _ = ack.send(());
+                    }
+                    Some(Command::Unsubscribe(user_id)) => {
+                        if sink.unsubscribe(channel(&user_id)).await.is_err() {
+                            break;
+                        }
+                    }
+                    None => return,
+                },
+                published = stream.next() => match published {
+                    Some(published) => {
+                        let Some(user_id) = published.get_channel_name().strip_prefix(CHANNEL_PREFIX)
+                        else {
+                            continue;
+                        };
+                        if let Ok(envelope) =
+                            serde_json::from_slice::<Envelope>(published.get_payload_bytes())
+                        {
+                            local.dispatch(user_id, envelope.device_id.as_deref(), envelope.push);
+                        }
+                    }
+                    None => break,
+                },
+            }
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// A live connection registered with the [`Hub`].
+pub struct Subscription {
+    hub: Hub,
+    user_id: String,
+    id: u64,
+    receiver: mpsc::Receiver<Push>,
+}
+
+impl Subscription {
+    /// `None` once the hub dropped the connection for falling behind.
+    pub async fn recv(&mut self) -> Option<Push> {
+        self.receiver.recv().await
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.hub.unsubscribe(&self.user_id, self.id);
+    }
+}
@@ -0,0 +1,23 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{event::models::Event, message::models::Message};
+
+/// Frames sent to the client, as JSON text.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame<'a> {
+    Message { message: &'a Message },
+    Acked { uuid: &'a str },
+    Event { event: &'a Event },
+    Error { message: &'a str },
+}
+
+/// Frames accepted from the client, as JSON text.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    /// The message was stored on the device and can be dropped server side.
+    Ack { uuid: String },
+    /// The user read an acked message; the sender may get a read receipt.
+    Read { uuid: String },
+}
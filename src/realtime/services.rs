@@ -0,0 +1,330 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    convert::Infallible,
+    time::Duration,
+};
+
+use axum::{
+    extract::ws::{Message as WsMessage, WebSocket},
+    response::sse::Event as SseEvent,
+};
+use futures::stream::{self, Stream, TryStreamExt};
+use mongodb::bson::doc;
+use tokio::time::{interval_at, Instant, Interval};
+
+use crate::{
+    auth::{jwt::Claims, whitelist::session_exists},
+    event::{
+        models::{Event, EventKind},
+        services::{get_events, MAX_EVENTS_PER_PAGE},
+    },
+    message::{
+        models::Message,
+        services::{mark_read, read_message},
+    },
+    realtime::{
+        hub::{Push, Subscription},
+        models::{ClientFrame, ServerFrame},
+    },
+    state::AppState,
+};
+
+/// How often an open socket checks that its session was not revoked.
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+async fn send_frame(socket: &mut WebSocket, frame: &ServerFrame<'_>) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(frame).expect("frames always serialize");
+    socket.send(WsMessage::Text(text.into())).await
+}
+
+/// Sends every pending message of the device, oldest first. Messages pushed
+/// live in the meantime are deduplicated through `delivered`.
+async fn replay_backlog(
+    state: &AppState,
+    socket: &mut WebSocket,
+    claims: &Claims,
+    delivered: &mut HashSet<String>,
+) -> Result<(), axum::Error> {
+    let cursor = state
+        .get_message_collection()
+        .find(doc! {
+            "receiver": &claims.sub,
+            "receiver_device": { "$in": [&claims.device_id, null] },
+        })
+        .sort(doc! { "created_at": 1, "uuid": 1 })
+        .await;
+    let mut cursor = match cursor {
+        Ok(cursor) => cursor,
+        Err(e) => return replay_failed(socket, e).await,
+    };
+    loop {
+        match cursor.try_next().await {
+            Ok(Some(message)) => send_message(socket, &message, delivered).await?,
+            Ok(None) => return Ok(()),
+            Err(e) => return replay_failed(socket, e).await,
+        }
+    }
+}
+
+/// Tells the client the backlog could not be fully replayed, then fails so
+/// the socket is closed: the client reconnects instead of silently missing
+/// messages.
+async fn replay_failed(
+    socket: &mut WebSocket,
+    e: mongodb::error::Error,
+) -> Result<(), axum::Error> {
+    send_frame(
+        socket,
+        &ServerFrame::Error {
+            message: "Failed to load pending messages",
+        },
+    )
+    .await?;
+    Err(axum::Error::new(e))
+}
+
+async fn send_message(
+    socket: &mut WebSocket,
+    message: &Message,
+    delivered: &mut HashSet<String>,
+) -> Result<(), axum::Error> {
+    if !delivered.insert(message.uuid.clone()) {
+        return Ok(());
+    }
+    send_frame(socket, &ServerFrame::Message { message }).await
+}
+
+async fn handle_client_frame(
+    state: &AppState,
+    socket: &mut WebSocket,
+    claims: &Claims,
+    delivered: &mut HashSet<String>,
+    text: &str,
+) -> Result<(), axum::Error> {
+    let Ok(frame) = serde_json::from_str::<ClientFrame>(text) else {
+        return send_frame(
+            socket,
+            &ServerFrame::Error {
+                message: "Invalid frame",
+            },
+        )
+        .await;
+    };
+
+    match frame {
+        ClientFrame::Ack { uuid } => {
+            let acked = read_message(state, &claims.sub, &claims.device_id, &uuid).await;
+            delivered.remove(&uuid);
+            match acked {
+                Ok(_) => send_frame(socket, &ServerFrame::Acked { uuid: &uuid }).await,
+                Err(_) => {
+                    send_frame(
+                        socket,
+                        &ServerFrame::Error {
+                            message: "Message not found in unread messages",
+                        },
+                    )
+                    .await
+                }
+            }
+        }
+        ClientFrame::Read { uuid } => match mark_read(state, &claims.sub, &uuid).await {
+            Ok(()) => Ok(()),
+            Err(_) => {
+                send_frame(
+                    socket,
+                    &ServerFrame::Error {
+                        message: "Message not found in delivered messages",
+                    },
+                )
+                .await
+            }
+        },
+    }
+}
+
+/// Serves one authenticated socket: replays the backlog, then forwards
+/// messages as they are sent, along with other inbox events, and deletes the
+/// messages the client acks. Closes once the session behind the token is
+/// revoked.
+pub async fn handle_socket(state: AppState, mut socket: WebSocket, claims: Claims) {
+    // Subscribe before replaying so nothing sent in between is missed.
+    let mut subscription = state.hub.subscribe(&claims.sub, &claims.device_id).await;
+    let mut delivered = HashSet::new();
+
+    if replay_backlog(&state, &mut socket, &claims, &mut delivered)
+        .await
+        .is_ok()
+    {
+        let mut session_check = tokio::time::interval(SESSION_CHECK_INTERVAL);
+        session_check.tick().await;
+
+        loop {
+            let result = tokio::select! {
+                push = subscription.recv() => match push {
+                    Some(Push::Message(message)) => {
+                        send_message(&mut socket, &message, &mut delivered).await
+                    }
+                    // Full messages are already pushed as such.
+                    Some(Push::Event(Event { kind: EventKind::MessageReceived { .. }, .. })) => Ok(()),
+                    Some(Push::Event(event)) => {
+                        send_frame(&mut socket, &ServerFrame::Event { event: &event }).await
+                    }
+                    None => {
+                        let _ = send_frame(
+                            &mut socket,
+                            &ServerFrame::Error { message: "Connection fell behind, reconnect" },
+                        )
+                        .await;
+                        break;
+                    }
+                },
+                frame = socket.recv() => match frame {
+                    Some(Ok(WsMessage::Text(text))) => {
+                        handle_client_frame(&state, &mut socket, &claims, &mut delivered, text.as_str())
+                            .await
+                    }
+                    Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => Ok(()),
+                },
+                _ = session_check.tick() => {
+                    if !session_exists(&state.redis, &claims.sub, &claims.device_id)
+                        .await
+                        .unwrap_or(true)
+                    {
+                        break;
+                    }
+                    Ok(())
+                }
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+fn sse_event(event: &Event) -> SseEvent {
+    SseEvent::default()
+        .id(&event.uuid)
+        .event(event.kind.name())
+        .data(serde_json::to_string(event).expect("events always serialize"))
+}
+
+/// Where the SSE stream is at: still reading stored events page by page after
+/// the event `after`, or forwarding live ones.
+enum Phase {
+    Replay { after: Option<String>, first: bool },
+    Live,
+}
+
+struct EventStream {
+    state: AppState,
+    claims: Claims,
+    subscription: Subscription,
+    session_check: Interval,
+    phase: Phase,
+    /// Current replay page, not sent yet.
+    pending: VecDeque<Event>,
+    /// Highest `seq` replayed; live pushes up to it were already sent.
+    replayed_through: i64,
+}
+
+impl EventStream {
+    /// Next replay page into `pending`. An unknown `Last-Event-ID` (e.g. from
+    /// another account) replays from the start. `false` when the stored
+    /// events could not be read, which ends the stream so the client resumes.
+    async fn load_page(&mut self) -> bool {
+        let Phase::Replay { after, first } = &mut self.phase else {
+            return true;
+        };
+        let events = self.state.get_event_collection();
+        let page = match get_events(events, &self.claims.sub, after.as_deref()).await {
+            Ok(page) => page,
+            Err(_) if *first && after.is_some() => {
+                *after = None;
+                return true;
+            }
+            Err(_) => return false,
+        };
+        *first = false;
+        if let Some(last) = page.last() {
+            *after = Some(last.uuid.clone());
+        }
+        if (page.len() as i64) < MAX_EVENTS_PER_PAGE {
+            self.phase = Phase::Live;
+        }
+        self.pending.extend(page);
+        true
+    }
+
+    async fn next_event(&mut self) -> Option<Event> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                self.replayed_through = self.replayed_through.max(event.seq);
+                if event.is_for_device(&self.claims.device_id) {
+                    return Some(event);
+                }
+                continue;
+            }
+            if matches!(self.phase, Phase::Replay { .. }) {
+                if !self.load_page().await {
+                    return None;
+                }
+                continue;
+            }
+
+            tokio::select! {
+                push = self.subscription.recv() => match push {
+                    Some(Push::Event(event))
+                        if event.seq > self.replayed_through
+                            && event.is_for_device(&self.claims.device_id) =>
+                    {
+                        return Some(event);
+                    }
+                    Some(_) => {}
+                    None => return None,
+                },
+                _ = self.session_check.tick() => {
+                    if !session_exists(&self.state.redis, &self.claims.sub, &self.claims.device_id)
+                        .await
+                        .unwrap_or(true)
+                    {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Inbox events for the SSE stream: everything stored after `last_event_id`,
+/// read one page at a time, then events as they happen. Ends once the session
+/// behind the token is revoked.
+pub async fn event_stream(
+    state: AppState,
+    claims: Claims,
+    last_event_id: Option<String>,
+) -> impl Stream<Item = Result<SseEvent, Infallible>> {
+    // Subscribe before replaying so nothing stored in between is missed.
+    let subscription = state.hub.subscribe(&claims.sub, &claims.device_id).await;
+    let events = EventStream {
+        session_check: interval_at(
+            Instant::now() + SESSION_CHECK_INTERVAL,
+            SESSION_CHECK_INTERVAL,
+        ),
+        state,
+        claims,
+        subscription,
+        phase: Phase::Replay {
+            after: last_event_id,
+            first: true,
+        },
+        pending: VecDeque::new(),
+        replayed_through: 0,
+    };
+    stream::unfold(events, |mut events| async move {
+        let event = events.next_event().await?;
+        Some((Ok(sse_event(&event)), events))
+    })
+}
@@ -1,48 +1,77 @@
 use axum::{
-    extract::State,
+    extract::{Extension, Path, State},
     http::{HeaderMap, StatusCode},
     middleware,
-    routing::{get, post},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
     Json, Router,
 };
 
 use crate::{
     auth::{
-        jwt::require_refresh_token,
-        model::{LoginPayload, RegisterPayload},
+        jwt::{require_access_token, require_refresh_token, Claims},
+        model::{
+            ChangePasswordPayload, DisableTotpPayload, LoginPayload, RegisterPayload, SessionInfo,
+            SessionMetadata, TotpCodePayload, TwoFactorLoginPayload,
+        },
         services,
+        throttle::login_lockout,
     },
     state::AppState,
+    utils::{
+        error::too_many_requests,
+        rate_limit::{RateLimitLayer, AUTH, REGISTER},
+        request::ClientAddr,
+    },
 };
-use serde_json::Value;
+use serde_json::{json, Value};
 
 async fn login(
     State(state): State<AppState>,
+    client_addr: Option<Extension<ClientAddr>>,
+    headers: HeaderMap,
     Json(payload): Json<LoginPayload>,
-) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+) -> Result<Json<Value>, Response> {
+    let metadata = SessionMetadata::from_headers(&headers, payload.device_name.clone());
+    let client_addr = client_addr.map(|Extension(addr)| addr);
+    let lockout = login_lockout(
+        &state.redis,
+        &payload.username,
+        client_addr.map(|ClientAddr(ip)| ip),
+    )
+    .await
+    .unwrap_or(None);
+    if let Some(retry_after) = lockout {
+        return Err(too_many_requests(retry_after));
+    }
+
     services::login(
         state.get_user_collection(),
-        state.secret_store,
+        &state.keyring,
         state.redis,
-        payload.username,
-        payload.password,
+        &state.password_policy,
+        payload,
+        metadata,
+        client_addr,
     )
     .await
+    .map_err(IntoResponse::into_response)
 }
 
 async fn register(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(payload): Json<RegisterPayload>,
 ) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let metadata = SessionMetadata::from_headers(&headers, payload.device_name.clone());
     services::register(
         state.get_user_collection(),
-        state.secret_store,
+        &state.get_transparency_log_collection(),
+        &state.keyring,
         state.redis,
-        payload.username,
-        payload.password,
-        payload.ik_pub,
-        payload.spk_pub,
-        payload.opk_pub,
+        &state.password_policy,
+        payload,
+        metadata,
     )
     .await
 }
@@ -54,6 +83,87 @@ async fn refresh_token(
     services::refresh_token(&state, headers).await
 }
 
+async fn logout(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    services::logout(&state.redis, &claims.sub, &claims.device_id).await?;
+    Ok(Json(json!({"message": "Logged out"})))
+}
+
+async fn logout_all(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    services::logout_all(&state.redis, &user_id).await?;
+    Ok(Json(json!({"message": "Logged out from all sessions"})))
+}
+
+async fn list_sessions(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<SessionInfo>>, (StatusCode, Json<Value>)> {
+    let sessions = services::list_sessions(&state.redis, &claims.sub, &claims.device_id).await?;
+    Ok(Json(sessions))
+}
+
+async fn revoke_session(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Path(session_id): Path<String>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    services::revoke_session_by_id(&state.redis, &user_id, &session_id).await?;
+    Ok(Json(json!({"message": "Session revoked"})))
+}
+
+async fn change_password(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<ChangePasswordPayload>,
+) -> Result<Json<Value>, Response> {
+    services::change_password(&state, &claims.sub, &claims.device_id, payload).await?;
+    Ok(Json(json!({"message": "Password changed"})))
+}
+
+async fn setup_totp(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    services::setup_totp(state.get_user_collection(), &user_id).await
+}
+
+async fn confirm_totp(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Json(payload): Json<TotpCodePayload>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    services::confirm_totp(&state, &user_id, &payload.code).await
+}
+
+async fn disable_totp(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Json(payload): Json<DisableTotpPayload>,
+) -> Result<Json<Value>, Response> {
+    services::disable_totp(&state, &user_id, payload).await?;
+    Ok(Json(
+        json!({"message": "Two-factor authentication disabled"}),
+    ))
+}
+
+async fn verify_two_factor_login(
+    State(state): State<AppState>,
+    client_addr: Option<Extension<ClientAddr>>,
+    Json(payload): Json<TwoFactorLoginPayload>,
+) -> Result<Json<Value>, Response> {
+    let client_addr = client_addr.map(|Extension(addr)| addr);
+    services::verify_two_factor_login(&state, payload, client_addr).await
+}
+
+async fn get_jwks(State(state): State<AppState>) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    services::get_jwks(&state.keyring).await
+}
+
 pub fn auth_routes(app_state: AppState) -> Router<AppState> {
     let protected_by_refresh_routes = Router::new()
         .route("/refresh", get(refresh_token))
@@ -61,11 +171,31 @@ pub fn auth_routes(app_state: AppState) -> Router<AppState> {
             app_state.clone(),
             require_refresh_token,
         ));
+    let protected_by_access_routes = Router::new()
+        .route("/logout", post(logout))
+        .route("/logout-all", post(logout_all))
+        .route("/password", post(change_password))
+        .route("/2fa/setup", post(setup_totp))
+        .route("/2fa/confirm", post(confirm_totp))
+        .route("/2fa/disable", post(disable_totp))
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/{session_id}", delete(revoke_session))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            require_access_token,
+        ));
     let public = Router::new()
-        .route("/register", post(register))
-        .route("/login", post(login));
+        .route(
+            "/register",
+            post(register).layer(RateLimitLayer::new(app_state.redis.clone(), REGISTER)),
+        )
+        .route("/login", post(login))
+        .route("/2fa/verify", post(verify_two_factor_login))
+        .route_layer(RateLimitLayer::new(app_state.redis.clone(), AUTH));
 
     Router::new()
+        .route("/.well-known/jwks.json", get(get_jwks))
         .nest("/auth", public)
         .nest("/auth", protected_by_refresh_routes)
+        .nest("/auth", protected_by_access_routes)
 }
@@ -0,0 +1,48 @@
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::get,
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    auth::jwt::validate_access_token,
+    realtime::services,
+    state::AppState,
+    utils::{error::error_response, request::stream_token},
+};
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    token: Option<String>,
+}
+
+async fn stream_events(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<EventsQuery>,
+) -> Result<Response, (StatusCode, Json<Value>)> {
+    let token = stream_token(&headers, query.token)
+        .ok_or_else(|| error_response(StatusCode::UNAUTHORIZED, Some("Missing token")))?;
+    let claims = validate_access_token(&state, &token).await?;
+
+    let last_event_id = headers
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .filter(|id| !id.is_empty());
+    let stream = services::event_stream(state, claims, last_event_id).await;
+    Ok(Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+pub fn events_routes() -> Router<AppState> {
+    Router::new().route("/events", get(stream_events))
+}
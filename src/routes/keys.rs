@@ -0,0 +1,179 @@
+use axum::{
+    extract::{Extension, Path, Query, State},
+    http::StatusCode,
+    middleware,
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    auth::jwt::{require_access_token, Claims},
+    keys::{
+        models::{DeviceInfo, Fingerprint, OpkCount, PreKeyBundle},
+        payload::{
+            DeviceRegistrationPayload, IdentityKeyPayload, OpkUploadPayload, SpkRotationPayload,
+        },
+        services,
+    },
+    state::AppState,
+    transparency::{
+        self,
+        models::{ConsistencyProof, SignedTreeHead},
+    },
+    utils::rate_limit::{RateLimitLayer, KEYS},
+};
+
+async fn get_bundle(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Path(target_id): Path<String>,
+) -> Result<Json<PreKeyBundle>, (StatusCode, Json<Value>)> {
+    let bundle = services::get_bundle(&state, &user_id, &target_id, None).await?;
+    Ok(Json(bundle))
+}
+
+async fn get_device_bundle(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Path((target_id, device_id)): Path<(String, String)>,
+) -> Result<Json<PreKeyBundle>, (StatusCode, Json<Value>)> {
+    let bundle = services::get_bundle(&state, &user_id, &target_id, Some(&device_id)).await?;
+    Ok(Json(bundle))
+}
+
+async fn get_fingerprint(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Path(target_id): Path<String>,
+) -> Result<Json<Fingerprint>, (StatusCode, Json<Value>)> {
+    let fingerprint =
+        services::get_fingerprint(state.get_user_collection(), &user_id, &target_id).await?;
+    Ok(Json(fingerprint))
+}
+
+async fn upload_opks(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<OpkUploadPayload>,
+) -> Result<Json<OpkCount>, (StatusCode, Json<Value>)> {
+    let count = services::upload_opks(
+        state.get_user_collection(),
+        &claims.sub,
+        &claims.device_id,
+        payload,
+    )
+    .await?;
+    Ok(Json(count))
+}
+
+async fn get_opk_count(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<OpkCount>, (StatusCode, Json<Value>)> {
+    let count =
+        services::get_opk_count(state.get_user_collection(), &claims.sub, &claims.device_id)
+            .await?;
+    Ok(Json(count))
+}
+
+async fn rotate_spk(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<SpkRotationPayload>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let rotated = services::rotate_spk(
+        state.get_user_collection(),
+        &state.key_policy,
+        &claims.sub,
+        &claims.device_id,
+        payload,
+    )
+    .await?;
+    Ok(Json(rotated))
+}
+
+async fn replace_identity_key(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Json(payload): Json<IdentityKeyPayload>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let replaced = services::replace_identity_key(&state, &user_id, payload).await?;
+    Ok(Json(replaced))
+}
+
+async fn register_device(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<DeviceRegistrationPayload>,
+) -> Result<Json<DeviceInfo>, (StatusCode, Json<Value>)> {
+    let device = services::register_device(&state, &claims.sub, &claims.device_id, payload).await?;
+    Ok(Json(device))
+}
+
+async fn remove_device(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Path(device_id): Path<String>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    services::remove_device(&state, &user_id, &device_id).await?;
+    Ok(Json(json!({"message": "Device removed"})))
+}
+
+async fn list_devices(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<String>,
+    Path(target_id): Path<String>,
+) -> Result<Json<Vec<DeviceInfo>>, (StatusCode, Json<Value>)> {
+    let devices = services::list_devices(state.get_user_collection(), &user_id, &target_id).await?;
+    Ok(Json(devices))
+}
+
+async fn get_tree_head(
+    State(state): State<AppState>,
+) -> Result<Json<SignedTreeHead>, (StatusCode, Json<Value>)> {
+    let head = transparency::services::get_tree_head(&state).await?;
+    Ok(Json(head))
+}
+
+#[derive(Deserialize)]
+struct ConsistencyQuery {
+    first: u64,
+    second: u64,
+}
+
+async fn get_consistency_proof(
+    State(state): State<AppState>,
+    Query(query): Query<ConsistencyQuery>,
+) -> Result<Json<ConsistencyProof>, (StatusCode, Json<Value>)> {
+    let proof =
+        transparency::services::get_consistency_proof(&state, query.first, query.second).await?;
+    Ok(Json(proof))
+}
+
+pub fn keys_routes(app_state: AppState) -> Router<AppState> {
+    let protected = Router::new()
+        .route("/opk", post(upload_opks))
+        .route("/opk/count", get(get_opk_count))
+        .route("/spk", post(rotate_spk))
+        .route("/identity", put(replace_identity_key))
+        .route("/transparency/head", get(get_tree_head))
+        .route("/transparency/consistency", get(get_consistency_proof))
+        .route("/{user_id}/bundle", get(get_bundle))
+        .route("/{user_id}/fingerprint", get(get_fingerprint))
+        .route("/devices", post(register_device))
+        .route("/devices/{device_id}", delete(remove_device))
+        .route("/{user_id}/devices", get(list_devices))
+        .route(
+            "/{user_id}/devices/{device_id}/bundle",
+            get(get_device_bundle),
+        )
+        .route_layer(RateLimitLayer::new(app_state.redis.clone(), KEYS))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            require_access_token,
+        ));
+
+    Router::new().nest("/keys", protected)
+}
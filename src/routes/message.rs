@@ -1,10 +1,12 @@
 use crate::{
-    auth::jwt::require_access_token,
+    auth::jwt::{require_access_token, Claims},
     message::{
-        models::{self, Message},
+        models::{self, Message, MessageBatch},
+        payload::{AckPayload, FetchPayload},
         services,
     },
     state::AppState,
+    utils::rate_limit::{RateLimitLayer, MESSAGE, MESSAGE_SEND},
 };
 use axum::{
     extract::Path,
@@ -21,27 +23,68 @@ use serde_json::{json, Value};
 
 async fn send_message(
     State(state): State<AppState>,
-    Extension(user_id): Extension<String>,
+    Extension(claims): Extension<Claims>,
     Json(message): Json<models::Message>,
 ) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
-    services::send_message(state.get_user_collection(), &user_id, message).await?;
+    services::send_message(&state, &claims.sub, &claims.device_id, message).await?;
     Ok(Json(json!({"status": "Message sent successfully"})))
 }
 
 async fn read_message(
     State(state): State<AppState>,
-    Extension(user_id): Extension<String>,
+    Extension(claims): Extension<Claims>,
     Path(message_id): Path<String>,
 ) -> Result<Json<Message>, (StatusCode, Json<Value>)> {
     let message =
-        services::read_message(state.get_user_collection(), &user_id, &message_id).await?;
+        services::read_message(&state, &claims.sub, &claims.device_id, &message_id).await?;
     Ok(Json(message))
 }
 
+async fn fetch_messages(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<FetchPayload>,
+) -> Result<Json<MessageBatch>, (StatusCode, Json<Value>)> {
+    let batch = services::fetch_messages(
+        state.get_message_collection(),
+        &claims.sub,
+        &claims.device_id,
+        payload.limit,
+    )
+    .await?;
+    Ok(Json(batch))
+}
+
+async fn ack_messages(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<AckPayload>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let acked =
+        services::ack_messages(&state, &claims.sub, &claims.device_id, payload.uuids).await?;
+    Ok(Json(json!({ "acked": acked })))
+}
+
+async fn mark_read(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(message_id): Path<String>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    services::mark_read(&state, &claims.sub, &message_id).await?;
+    Ok(Json(json!({"status": "Message marked as read"})))
+}
+
 pub fn message_routes(app_state: AppState) -> Router<AppState> {
     let protected = Router::new()
-        .route("/send", post(send_message))
+        .route(
+            "/send",
+            post(send_message).layer(RateLimitLayer::new(app_state.redis.clone(), MESSAGE_SEND)),
+        )
         .route("/read/{message_id}", get(read_message))
+        .route("/fetch", post(fetch_messages))
+        .route("/ack", post(ack_messages))
+        .route("/{message_id}/read", post(mark_read))
+        .route_layer(RateLimitLayer::new(app_state.redis.clone(), MESSAGE))
         .route_layer(middleware::from_fn_with_state(
             app_state.clone(),
             require_access_token,
@@ -1,4 +1,7 @@
 pub mod auth;
+pub mod events;
+pub mod keys;
 pub mod message;
 pub mod system;
 pub mod user;
+pub mod ws;
@@ -1,30 +1,34 @@
 use crate::{
-    auth::jwt::require_access_token,
+    auth::jwt::{require_access_token, Claims},
+    event::{models::Event, services as event_services},
     state::AppState,
     user::{
-        models::{MessageInfo, UserPrivate, UserPublic, UserResponse},
+        models::{MessageListing, UserPrivate, UserPublic, UserResponse},
         payload::UserUpdatePayload,
         services,
     },
-};
-use axum::{
-    extract::Path,
-    middleware,
-    routing::{delete, get, patch, post},
-    Router,
+    utils::rate_limit::{RateLimitLayer, FRIEND_REQUESTS, USER},
 };
 use axum::{
     extract::{Extension, State},
     http::StatusCode,
     Json,
 };
+use axum::{
+    extract::{Path, Query},
+    middleware,
+    routing::{delete, get, patch, post},
+    Router,
+};
+use serde::Deserialize;
 use serde_json::{json, Value};
 
 async fn get_profile(
     State(state): State<AppState>,
-    Extension(user_id): Extension<String>,
+    Extension(claims): Extension<Claims>,
 ) -> Result<Json<UserPrivate>, (StatusCode, Json<Value>)> {
-    let user = services::get_profile(state.get_user_collection(), &user_id).await?;
+    let user =
+        services::get_profile(state.get_user_collection(), &claims.sub, &claims.device_id).await?;
     Ok(Json(user))
 }
 
@@ -46,11 +50,16 @@ async fn get_all(
 
 async fn update_user(
     State(state): State<AppState>,
-    Extension(user_id): Extension<String>,
+    Extension(claims): Extension<Claims>,
     Json(payload): Json<UserUpdatePayload>,
 ) -> Result<Json<UserPrivate>, (StatusCode, Json<Value>)> {
-    let updated_user =
-        services::update_user(state.get_user_collection(), &user_id, payload).await?;
+    let updated_user = services::update_user(
+        state.get_user_collection(),
+        &claims.sub,
+        &claims.device_id,
+        payload,
+    )
+    .await?;
     Ok(Json(updated_user))
 }
 
@@ -58,7 +67,7 @@ async fn delete_user(
     State(state): State<AppState>,
     Extension(user_id): Extension<String>,
 ) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
-    services::delete_user(state.get_user_collection(), &user_id).await?;
+    services::delete_user(&state, &user_id).await?;
     Ok(Json(json!({"message": "User deleted successfully"})))
 }
 
@@ -67,8 +76,7 @@ async fn request_friendship(
     Path(username): Path<String>,
     Extension(user_id): Extension<String>,
 ) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
-    let result =
-        services::request_friendship(state.get_user_collection(), &user_id, &username).await;
+    let result = services::request_friendship(&state, &user_id, &username).await;
     match result {
         Ok(value) => Ok(value),
         Err(err) => Err(err),
@@ -80,8 +88,7 @@ async fn accept_friendship(
     Path(username): Path<String>,
     Extension(user_id): Extension<String>,
 ) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
-    let result =
-        services::accept_friendship(state.get_user_collection(), &user_id, &username).await;
+    let result = services::accept_friendship(&state, &user_id, &username).await;
     match result {
         Ok(_) => Ok(Json(json!({"message": "Friend request accepted"}))),
         Err(err) => Err(err),
@@ -114,26 +121,86 @@ async fn remove_friendship(
     }
 }
 
+#[derive(Deserialize)]
+struct MessagesQuery {
+    cursor: Option<String>,
+    sender: Option<String>,
+    limit: Option<i64>,
+}
+
 async fn get_messages(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Query(query): Query<MessagesQuery>,
+) -> Result<Json<MessageListing>, (StatusCode, Json<Value>)> {
+    if query.cursor.is_none() && query.sender.is_none() && query.limit.is_none() {
+        let messages = services::get_all_messages(
+            state.get_message_collection(),
+            &claims.sub,
+            &claims.device_id,
+        )
+        .await?;
+        return Ok(Json(MessageListing::All(messages)));
+    }
+
+    let page = services::get_messages(
+        state.get_message_collection(),
+        &claims.sub,
+        &claims.device_id,
+        query.cursor.as_deref(),
+        query.sender.as_deref(),
+        query.limit,
+    )
+    .await?;
+    Ok(Json(MessageListing::Page(page)))
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    after: Option<String>,
+}
+
+async fn get_events(
     State(state): State<AppState>,
     Extension(user_id): Extension<String>,
-) -> Result<Json<Vec<MessageInfo>>, (StatusCode, Json<Value>)> {
-    let messages = services::get_messages(state.get_user_collection(), &user_id).await?;
-    Ok(Json(messages))
+    Query(query): Query<EventsQuery>,
+) -> Result<Json<Vec<Event>>, (StatusCode, Json<Value>)> {
+    let events = event_services::get_events(
+        state.get_event_collection(),
+        &user_id,
+        query.after.as_deref(),
+    )
+    .await?;
+    Ok(Json(events))
 }
 
 pub fn user_routes(app_state: AppState) -> Router<AppState> {
+    let friends_limit = RateLimitLayer::new(app_state.redis.clone(), FRIEND_REQUESTS);
     let protected = Router::new()
         .route("/me", get(get_profile))
         .route("/", get(get_all))
         .route("/", patch(update_user))
         .route("/", delete(delete_user))
         .route("/{id}", get(get_by_id))
-        .route("/{id}/friends", post(request_friendship))
-        .route("/{id}/friends/accept", post(accept_friendship))
-        .route("/{id}/friends/reject", post(reject_friendship))
-        .route("/{id}/friends", delete(remove_friendship))
+        .route(
+            "/{id}/friends",
+            post(request_friendship).layer(friends_limit.clone()),
+        )
+        .route(
+            "/{id}/friends/accept",
+            post(accept_friendship).layer(friends_limit.clone()),
+        )
+        .route(
+            "/{id}/friends/reject",
+            post(reject_friendship).layer(friends_limit.clone()),
+        )
+        .route(
+            "/{id}/friends",
+            delete(remove_friendship).layer(friends_limit),
+        )
         .route("/messages", get(get_messages))
+        .route("/events", get(get_events))
+        .route_layer(RateLimitLayer::new(app_state.redis.clone(), USER))
         .route_layer(middleware::from_fn_with_state(
             app_state.clone(),
             require_access_token,
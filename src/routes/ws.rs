@@ -0,0 +1,38 @@
+use axum::{
+    extract::{ws::WebSocketUpgrade, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+    routing::get,
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    auth::jwt::validate_access_token,
+    realtime::services,
+    state::AppState,
+    utils::{error::error_response, request::stream_token},
+};
+
+#[derive(Deserialize)]
+struct ConnectQuery {
+    token: Option<String>,
+}
+
+async fn connect(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<ConnectQuery>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, (StatusCode, Json<Value>)> {
+    let token = stream_token(&headers, query.token)
+        .ok_or_else(|| error_response(StatusCode::UNAUTHORIZED, Some("Missing token")))?;
+
+    let claims = validate_access_token(&state, &token).await?;
+    Ok(ws.on_upgrade(move |socket| services::handle_socket(state, socket, claims)))
+}
+
+pub fn ws_routes() -> Router<AppState> {
+    Router::new().route("/ws", get(connect))
+}
@@ -1,13 +1,28 @@
 use mongodb::Collection;
 use shuttle_runtime::SecretStore;
 
-use crate::user::models::User;
+use crate::{
+    auth::{keyring::KeyRing, model::SecurityEvent, password::PasswordPolicy},
+    event::models::Event,
+    keys::models::KeyPolicy,
+    message::models::Message,
+    realtime::hub::Hub,
+    transparency::{cache::LogCache, models::LogEntry},
+    user::models::User,
+    utils::request::ProxyPolicy,
+};
 
 #[derive(Clone)]
 pub struct AppState {
     pub mongo: mongodb::Client,
     pub secret_store: SecretStore,
     pub redis: redis::Client,
+    pub keyring: KeyRing,
+    pub password_policy: PasswordPolicy,
+    pub key_policy: KeyPolicy,
+    pub proxy_policy: ProxyPolicy,
+    pub hub: Hub,
+    pub transparency_cache: LogCache,
     pub started_at: std::time::Instant,
 }
 
@@ -15,4 +30,22 @@ impl AppState {
     pub fn get_user_collection(&self) -> Collection<User> {
         self.mongo.database("lucchat").collection("users")
     }
+
+    pub fn get_security_event_collection(&self) -> Collection<SecurityEvent> {
+        self.mongo.database("lucchat").collection("security_events")
+    }
+
+    pub fn get_message_collection(&self) -> Collection<Message> {
+        self.mongo.database("lucchat").collection("messages")
+    }
+
+    pub fn get_transparency_log_collection(&self) -> Collection<LogEntry> {
+        self.mongo
+            .database("lucchat")
+            .collection("transparency_log")
+    }
+
+    pub fn get_event_collection(&self) -> Collection<Event> {
+        self.mongo.database("lucchat").collection("events")
+    }
 }
@@ -17,7 +17,7 @@ pub async fn get_health(state: &AppState) -> Result<Json<Value>, (StatusCode, Js
         _ => "down",
     };
 
-    let redis_status = match timeout(Duration::from_secs(5), async {
+    let redis_status = timeout(Duration::from_secs(5), async {
         match state.redis.get_multiplexed_tokio_connection().await {
             Ok(mut conn) => match redis::cmd("PING").query_async::<String>(&mut conn).await {
                 Ok(_) => "up",
@@ -27,10 +27,7 @@ pub async fn get_health(state: &AppState) -> Result<Json<Value>, (StatusCode, Js
         }
     })
     .await
-    {
-        Ok(status) => status,
-        Err(_) => "down",
-    };
+    .unwrap_or("down");
 
     let dependencies = json!({
         "mongo": mongo_status,
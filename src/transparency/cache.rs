@@ -0,0 +1,37 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::RwLock;
+
+use crate::transparency::{merkle::Tree, models::SignedTreeHead};
+
+/// How long the cached tree is served before entries appended by other
+/// instances are read again.
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// This instance's copy of the log's Merkle tree and its latest signed head.
+/// Only entries appended since the last sync are read from the database.
+#[derive(Clone, Default)]
+pub struct LogCache {
+    pub(crate) inner: Arc<RwLock<CachedLog>>,
+}
+
+#[derive(Default)]
+pub(crate) struct CachedLog {
+    pub tree: Tree,
+    /// Signed over the whole of `tree`; `None` until the first sync.
+    pub head: Option<SignedTreeHead>,
+    pub synced_at: Option<Instant>,
+}
+
+impl CachedLog {
+    pub fn is_fresh(&self, min_size: usize) -> bool {
+        self.head.is_some()
+            && self.tree.len() >= min_size
+            && self
+                .synced_at
+                .is_some_and(|synced_at| synced_at.elapsed() < SYNC_INTERVAL)
+    }
+}
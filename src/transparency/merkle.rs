@@ -0,0 +1,326 @@
+//! Merkle tree hashing and proofs as specified in RFC 6962 section 2.
+
+use sha2::{Digest, Sha256};
+
+pub type Hash = [u8; 32];
+
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    Sha256::new()
+        .chain_update([0x00])
+        .chain_update(data)
+        .finalize()
+        .into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    Sha256::new()
+        .chain_update([0x01])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+/// Largest power of two strictly smaller than `n` (`n` > 1).
+fn split_point(n: usize) -> usize {
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+/// Append-only Merkle tree that keeps the hash of every perfect, aligned
+/// subtree: level `l` holds the roots over leaves `j * 2^l .. (j + 1) * 2^l`.
+/// Appends cost O(1) amortized hashes and roots or proofs for any prefix of
+/// the tree O(log n), instead of rehashing every leaf.
+#[derive(Debug, Clone, Default)]
+pub struct Tree {
+    levels: Vec<Vec<Hash>>,
+}
+
+impl Tree {
+    pub fn len(&self) -> usize {
+        self.levels.first().map_or(0, Vec::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&mut self, leaf: Hash) {
+        if self.levels.is_empty() {
+            self.levels.push(Vec::new());
+        }
+        self.levels[0].push(leaf);
+        let mut level = 0;
+        while self.levels[level].len().is_multiple_of(2) {
+            let nodes = &self.levels[level];
+            let parent = node_hash(&nodes[nodes.len() - 2], &nodes[nodes.len() - 1]);
+            if self.levels.len() == level + 1 {
+                self.levels.push(Vec::new());
+            }
+            self.levels[level + 1].push(parent);
+            level += 1;
+        }
+    }
+
+    /// MTH over leaves `start..end`, where `start` is aligned as in the
+    /// RFC 6962 recursion from the first leaf.
+    fn range_root(&self, start: usize, end: usize) -> Hash {
+        let n = end - start;
+        if n.is_power_of_two() {
+            let level = n.trailing_zeros();
+            return self.levels[level as usize][start >> level];
+        }
+        let k = split_point(n);
+        node_hash(
+            &self.range_root(start, start + k),
+            &self.range_root(start + k, end),
+        )
+    }
+
+    /// MTH over the first `size` leaves (`size` <= `len`).
+    pub fn root(&self, size: usize) -> Hash {
+        if size == 0 {
+            return Sha256::digest([]).into();
+        }
+        self.range_root(0, size)
+    }
+
+    /// Audit path proving that leaf `index` is part of the tree over the
+    /// first `size` leaves.
+    pub fn inclusion_proof(&self, index: usize, size: usize) -> Vec<Hash> {
+        if size <= 1 || index >= size {
+            return Vec::new();
+        }
+        self.path(index, 0, size)
+    }
+
+    fn path(&self, index: usize, start: usize, end: usize) -> Vec<Hash> {
+        let n = end - start;
+        if n <= 1 {
+            return Vec::new();
+        }
+        let k = split_point(n);
+        if index < start + k {
+            let mut path = self.path(index, start, start + k);
+            path.push(self.range_root(start + k, end));
+            path
+        } else {
+            let mut path = self.path(index, start + k, end);
+            path.push(self.range_root(start, start + k));
+            path
+        }
+    }
+
+    /// Proof that the tree over the first `old_size` leaves is a prefix of
+    /// the tree over the first `size`, i.e. the log was only appended to.
+    pub fn consistency_proof(&self, old_size: usize, size: usize) -> Vec<Hash> {
+        if old_size == 0 || old_size >= size {
+            return Vec::new();
+        }
+        self.subproof(old_size, 0, size, true)
+    }
+
+    fn subproof(&self, m: usize, start: usize, end: usize, complete: bool) -> Vec<Hash> {
+        let n = end - start;
+        if m == n {
+            return if complete {
+                Vec::new()
+            } else {
+                vec![self.range_root(start, end)]
+            };
+        }
+        let k = split_point(n);
+        if m <= k {
+            let mut proof = self.subproof(m, start, start + k, complete);
+            proof.push(self.range_root(start + k, end));
+            proof
+        } else {
+            let mut proof = self.subproof(m - k, start + k, end, false);
+            proof.push(self.range_root(start, start + k));
+            proof
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data_encoding::HEXLOWER;
+
+    use super::*;
+
+    /// Leaves of the certificate-transparency reference test tree.
+    const LEAVES: [&str; 8] = [
+        "",
+        "00",
+        "10",
+        "2021",
+        "3031",
+        "40414243",
+        "5051525354555657",
+        "606162636465666768696a6b6c6d6e6f",
+    ];
+
+    fn hash(hex: &str) -> Hash {
+        HEXLOWER.decode(hex.as_bytes()).unwrap().try_into().unwrap()
+    }
+
+    fn tree() -> Tree {
+        let mut tree = Tree::default();
+        for leaf in LEAVES {
+            tree.push(leaf_hash(&HEXLOWER.decode(leaf.as_bytes()).unwrap()));
+        }
+        tree
+    }
+
+    /// RFC 9162 section 2.1.3.2: the root an audit path leads to.
+    fn root_from_path(index: usize, size: usize, leaf: Hash, path: &[Hash]) -> Option<Hash> {
+        let (mut fnode, mut snode) = (index, size - 1);
+        let mut root = leaf;
+        for sibling in path {
+            if snode == 0 {
+                return None;
+            }
+            if fnode & 1 == 1 || fnode == snode {
+                root = node_hash(sibling, &root);
+                while fnode & 1 == 0 && fnode != 0 {
+                    fnode >>= 1;
+                    snode >>= 1;
+                }
+            } else {
+                root = node_hash(&root, sibling);
+            }
+            fnode >>= 1;
+            snode >>= 1;
+        }
+        (snode == 0).then_some(root)
+    }
+
+    #[test]
+    fn roots_match_reference_vectors() {
+        let roots = [
+            "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d",
+            "fac54203e7cc696cf0dfcb42c92a1d9dbaf70ad9e621f4bd8d98662f00e3c125",
+            "aeb6bcfe274b70a14fb067a5e5578264db0fa9b51af5e0ba159158f329e06e77",
+            "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+            "4e3bbb1f7b478dcfe71fb631631519a3bca12c9aefca1612bfce4c13a86264d4",
+            "76e67dadbcdf1e10e1b74ddc608abd2f98dfb16fbce75277b5232a127f2087ef",
+            "ddb89be403809e325750d3d263cd78929c2942b7942a34b77e122c9594a74c8c",
+            "5dc9da79a70659a9ad559cb701ded9a2ab9d823aad2f4960cfe370eff4604328",
+        ];
+        let tree = tree();
+        assert_eq!(
+            tree.root(0),
+            hash("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+        for (size, root) in (1..).zip(roots) {
+            assert_eq!(tree.root(size), hash(root), "size {size}");
+        }
+    }
+
+    #[test]
+    fn inclusion_proofs_match_reference_vectors() {
+        let cases: [(usize, usize, &[&str]); 4] = [
+            (
+                0,
+                8,
+                &[
+                    "96a296d224f285c67bee93c30f8a309157f0daa35dc5b87e410b78630a09cfc7",
+                    "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+                    "6b47aaf29ee3c2af9af889bc1fb9254dabd31177f16232dd6aab035ca39bf6e4",
+                ],
+            ),
+            (
+                5,
+                8,
+                &[
+                    "bc1a0643b12e4d2d7c77918f44e0f4f79a838b6cf9ec5b5c283e1f4d88599e6b",
+                    "ca854ea128ed050b41b35ffc1b87b8eb2bde461e9e3b5596ece6b9d5975a0ae0",
+                    "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+                ],
+            ),
+            (
+                2,
+                3,
+                &["fac54203e7cc696cf0dfcb42c92a1d9dbaf70ad9e621f4bd8d98662f00e3c125"],
+            ),
+            (
+                1,
+                5,
+                &[
+                    "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d",
+                    "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+                    "bc1a0643b12e4d2d7c77918f44e0f4f79a838b6cf9ec5b5c283e1f4d88599e6b",
+                ],
+            ),
+        ];
+        let tree = tree();
+        for (index, size, path) in cases {
+            let expected: Vec<Hash> = path.iter().copied().map(hash).collect();
+            assert_eq!(
+                tree.inclusion_proof(index, size),
+                expected,
+                "leaf {index} of {size}"
+            );
+        }
+    }
+
+    #[test]
+    fn consistency_proofs_match_reference_vectors() {
+        let cases: [(usize, usize, &[&str]); 3] = [
+            (
+                1,
+                8,
+                &[
+                    "96a296d224f285c67bee93c30f8a309157f0daa35dc5b87e410b78630a09cfc7",
+                    "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+                    "6b47aaf29ee3c2af9af889bc1fb9254dabd31177f16232dd6aab035ca39bf6e4",
+                ],
+            ),
+            (
+                6,
+                8,
+                &[
+                    "0ebc5d3437fbe2db158b9f126a1d118e308181031d0a949f8dededebc558ef6a",
+                    "ca854ea128ed050b41b35ffc1b87b8eb2bde461e9e3b5596ece6b9d5975a0ae0",
+                    "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+                ],
+            ),
+            (
+                2,
+                5,
+                &[
+                    "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+                    "bc1a0643b12e4d2d7c77918f44e0f4f79a838b6cf9ec5b5c283e1f4d88599e6b",
+                ],
+            ),
+        ];
+        let tree = tree();
+        for (old_size, size, proof) in cases {
+            let expected: Vec<Hash> = proof.iter().copied().map(hash).collect();
+            assert_eq!(
+                tree.consistency_proof(old_size, size),
+                expected,
+                "{old_size} to {size}"
+            );
+        }
+    }
+
+    #[test]
+    fn audit_paths_rebuild_the_root() {
+        let tree = tree();
+        for size in 1..=LEAVES.len() {
+            for index in 0..size {
+                let leaf = tree.levels[0][index];
+                let path = tree.inclusion_proof(index, size);
+                assert_eq!(
+                    root_from_path(index, size, leaf, &path),
+                    Some(tree.root(size)),
+                    "leaf {index} of {size}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,4 @@
+pub mod cache;
+pub mod merkle;
+pub mod models;
+pub mod services;
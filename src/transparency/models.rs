@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+
+use crate::transparency::merkle::{leaf_hash, Hash};
+
+/// One published `(user_id, device_id, ik_pub, ik_version)` binding;
+/// `device_id` is `None` for the account keys. `index` is the leaf position
+/// in the log and never changes once written.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub index: i64,
+    pub user_id: String,
+    #[serde(default)]
+    pub device_id: Option<String>,
+    pub ik_pub: [u8; 32],
+    pub ik_version: i64,
+    pub leaf_hash: Hash,
+    pub created_at: i64,
+}
+
+impl LogEntry {
+    /// Leaf input: `len(user_id)` as u16 BE, `user_id`, for devices
+    /// `len(device_id)` as u16 BE and `device_id`, then `ik_pub` and
+    /// `ik_version` as u64 BE. Account leaves are told apart by their length.
+    pub fn leaf_data(
+        user_id: &str,
+        device_id: Option<&str>,
+        ik_pub: &[u8; 32],
+        ik_version: i64,
+    ) -> Vec<u8> {
+        let device_len = device_id.map_or(0, |device_id| 2 + device_id.len());
+        let mut data = Vec::with_capacity(2 + user_id.len() + device_len + 32 + 8);
+        data.extend_from_slice(&(user_id.len() as u16).to_be_bytes());
+        data.extend_from_slice(user_id.as_bytes());
+        if let Some(device_id) = device_id {
+            data.extend_from_slice(&(device_id.len() as u16).to_be_bytes());
+            data.extend_from_slice(device_id.as_bytes());
+        }
+        data.extend_from_slice(ik_pub);
+        data.extend_from_slice(&(ik_version as u64).to_be_bytes());
+        data
+    }
+
+    pub fn new(
+        index: i64,
+        user_id: &str,
+        device_id: Option<&str>,
+        ik_pub: [u8; 32],
+        ik_version: i64,
+    ) -> Self {
+        Self {
+            index,
+            user_id: user_id.to_string(),
+            device_id: device_id.map(str::to_string),
+            ik_pub,
+            ik_version,
+            leaf_hash: leaf_hash(&Self::leaf_data(user_id, device_id, &ik_pub, ik_version)),
+            created_at: chrono::Utc::now().timestamp(),
+        }
+    }
+}
+
+/// Root of the log at `tree_size`, signed with the JWT signing key (`kid` is
+/// listed in the JWKS). The signature covers `tree_size` as u64 BE,
+/// `timestamp` as i64 BE and `root_hash`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedTreeHead {
+    pub tree_size: u64,
+    pub timestamp: i64,
+    pub root_hash: Hash,
+    pub kid: String,
+    pub signature: Vec<u8>,
+}
+
+impl SignedTreeHead {
+    pub fn signed_data(tree_size: u64, timestamp: i64, root_hash: &Hash) -> Vec<u8> {
+        let mut data = Vec::with_capacity(8 + 8 + 32);
+        data.extend_from_slice(&tree_size.to_be_bytes());
+        data.extend_from_slice(&timestamp.to_be_bytes());
+        data.extend_from_slice(root_hash);
+        data
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InclusionProof {
+    pub leaf_index: u64,
+    pub leaf_hash: Hash,
+    pub audit_path: Vec<Hash>,
+}
+
+/// Proves the identity key in a bundle is the one the log publishes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransparencyProof {
+    pub inclusion: InclusionProof,
+    pub tree_head: SignedTreeHead,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConsistencyProof {
+    pub first: u64,
+    pub second: u64,
+    pub proof: Vec<Hash>,
+}
@@ -0,0 +1,227 @@
+use axum::{http::StatusCode, Json};
+use futures::stream::TryStreamExt;
+use mongodb::{
+    bson::{doc, Document},
+    options::IndexOptions,
+    Collection, IndexModel,
+};
+use serde_json::Value;
+use std::time::Instant;
+use tokio::sync::RwLockReadGuard;
+
+use crate::{
+    auth::keyring::KeyRing,
+    state::AppState,
+    transparency::{
+        cache::CachedLog,
+        merkle::Tree,
+        models::{ConsistencyProof, InclusionProof, LogEntry, SignedTreeHead, TransparencyProof},
+    },
+    utils::error::{error_response, is_duplicate_key},
+};
+
+/// Concurrent appends race for the next index; the loser retries this often.
+const APPEND_ATTEMPTS: usize = 5;
+
+/// Unique binding index from before device keys were logged.
+const ACCOUNT_BINDING_INDEX: &str = "user_id_1_ik_version_1";
+
+pub async fn create_indexes(log: &Collection<LogEntry>) -> mongodb::error::Result<()> {
+    // Already gone on every start but the first.
+    let _ = log.drop_index(ACCOUNT_BINDING_INDEX).await;
+
+    let unique = || IndexOptions::builder().unique(true).build();
+    log.create_indexes([
+        IndexModel::builder()
+            .keys(doc! { "index": 1 })
+            .options(unique())
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "user_id": 1, "device_id": 1, "ik_version": 1 })
+            .options(unique())
+            .build(),
+    ])
+    .await?;
+    Ok(())
+}
+
+fn binding_filter(user_id: &str, device_id: Option<&str>) -> Document {
+    doc! { "user_id": user_id, "device_id": device_id }
+}
+
+/// Version for a newly registered key set of `device_id`: one past the last
+/// one logged, so a device id reused after removal gets a new binding.
+pub async fn next_device_version(
+    log: &Collection<LogEntry>,
+    user_id: &str,
+    device_id: &str,
+) -> mongodb::error::Result<i64> {
+    let last = log
+        .find_one(binding_filter(user_id, Some(device_id)))
+        .sort(doc! { "ik_version": -1 })
+        .await?;
+    Ok(last.map_or(1, |last| last.ik_version + 1))
+}
+
+/// Publishes a binding, or returns the entry already published for it. The
+/// unique indexes turn concurrent appends into retries instead of gaps or
+/// duplicate leaves.
+pub async fn append(
+    log: &Collection<LogEntry>,
+    user_id: &str,
+    device_id: Option<&str>,
+    ik_pub: [u8; 32],
+    ik_version: i64,
+) -> mongodb::error::Result<LogEntry> {
+    let mut attempt = 0;
+    loop {
+        let mut filter = binding_filter(user_id, device_id);
+        filter.insert("ik_version", ik_version);
+        if let Some(entry) = log.find_one(filter).await? {
+            return Ok(entry);
+        }
+
+        let last = log.find_one(doc! {}).sort(doc! { "index": -1 }).await?;
+        let entry = LogEntry::new(
+            last.map_or(0, |last| last.index + 1),
+            user_id,
+            device_id,
+            ik_pub,
+            ik_version,
+        );
+        match log.insert_one(&entry).await {
+            Ok(_) => return Ok(entry),
+            Err(e) if is_duplicate_key(&e) && attempt + 1 < APPEND_ATTEMPTS => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Read access to the cached tree, first brought up to date with the log
+/// unless it synced recently and already holds `min_size` leaves. A sync
+/// reads only the new entries, stopping at the first gap so a half finished
+/// append is never part of a signed tree, and signs a head when the tree grew.
+async fn synced<'a>(
+    state: &'a AppState,
+    min_size: usize,
+) -> mongodb::error::Result<RwLockReadGuard<'a, CachedLog>> {
+    let cache = &state.transparency_cache.inner;
+    {
+        let cached = cache.read().await;
+        if cached.is_fresh(min_size) {
+            return Ok(cached);
+        }
+    }
+
+    let mut cached = cache.write().await;
+    if !cached.is_fresh(min_size) {
+        let mut entries = state
+            .get_transparency_log_collection()
+            .find(doc! { "index": { "$gte": cached.tree.len() as i64 } })
+            .sort(doc! { "index": 1 })
+            .await?;
+        while let Some(entry) = entries.try_next().await? {
+            if entry.index != cached.tree.len() as i64 {
+                break;
+            }
+            cached.tree.push(entry.leaf_hash);
+        }
+        let size = cached.tree.len() as u64;
+        if cached
+            .head
+            .as_ref()
+            .is_none_or(|head| head.tree_size != size)
+        {
+            cached.head = Some(sign_tree_head(&state.keyring, &cached.tree));
+        }
+        cached.synced_at = Some(Instant::now());
+    }
+    Ok(cached.downgrade())
+}
+
+fn sign_tree_head(keyring: &KeyRing, tree: &Tree) -> SignedTreeHead {
+    let tree_size = tree.len() as u64;
+    let timestamp = chrono::Utc::now().timestamp();
+    let root_hash = tree.root(tree.len());
+    let signature = keyring.sign_bytes(&SignedTreeHead::signed_data(
+        tree_size, timestamp, &root_hash,
+    ));
+    SignedTreeHead {
+        tree_size,
+        timestamp,
+        root_hash,
+        kid: keyring.signing_kid().to_string(),
+        signature,
+    }
+}
+
+/// Inclusion proof for a binding against the latest signed tree head.
+/// Bindings from before the log existed, or whose append failed, are appended
+/// on first use.
+pub async fn prove(
+    state: &AppState,
+    user_id: &str,
+    device_id: Option<&str>,
+    ik_pub: [u8; 32],
+    ik_version: i64,
+) -> mongodb::error::Result<Option<TransparencyProof>> {
+    let entry = append(
+        &state.get_transparency_log_collection(),
+        user_id,
+        device_id,
+        ik_pub,
+        ik_version,
+    )
+    .await?;
+    if entry.ik_pub != ik_pub {
+        return Ok(None);
+    }
+
+    let index = entry.index as usize;
+    let cached = synced(state, index + 1).await?;
+    let (Some(tree_head), true) = (&cached.head, index < cached.tree.len()) else {
+        return Ok(None);
+    };
+    Ok(Some(TransparencyProof {
+        inclusion: InclusionProof {
+            leaf_index: entry.index as u64,
+            leaf_hash: entry.leaf_hash,
+            audit_path: cached.tree.inclusion_proof(index, cached.tree.len()),
+        },
+        tree_head: tree_head.clone(),
+    }))
+}
+
+pub async fn get_tree_head(state: &AppState) -> Result<SignedTreeHead, (StatusCode, Json<Value>)> {
+    let cached = synced(state, 0)
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
+    cached
+        .head
+        .clone()
+        .ok_or(error_response(StatusCode::INTERNAL_SERVER_ERROR, None))
+}
+
+pub async fn get_consistency_proof(
+    state: &AppState,
+    first: u64,
+    second: u64,
+) -> Result<ConsistencyProof, (StatusCode, Json<Value>)> {
+    let cached = synced(state, second as usize)
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
+    if first == 0 || first > second || second > cached.tree.len() as u64 {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            Some("Invalid tree sizes"),
+        ));
+    }
+
+    Ok(ConsistencyProof {
+        first,
+        second,
+        proof: cached
+            .tree
+            .consistency_proof(first as usize, second as usize),
+    })
+}
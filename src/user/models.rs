@@ -1,4 +1,3 @@
-use crate::message::models::Message;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -13,7 +12,25 @@ pub struct User {
     pub friends_requests: Vec<String>,
     pub friends: Vec<String>,
     pub keys: Key,
-    pub unread_messages: Vec<Message>,
+    /// Bumped every time the identity key is replaced.
+    #[serde(default = "default_ik_version")]
+    pub ik_version: i64,
+    /// Devices holding their own key set, keyed by their auth `device_id`.
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    #[serde(default)]
+    pub totp: Option<TotpConfig>,
+    /// Whether senders learn when the user read their messages.
+    #[serde(default = "default_read_receipts")]
+    pub read_receipts: bool,
+}
+
+fn default_ik_version() -> i64 {
+    1
+}
+
+fn default_read_receipts() -> bool {
+    true
 }
 
 impl User {
@@ -25,12 +42,41 @@ impl User {
             description: None,
             profile_picture: None,
             keys,
+            ik_version: 1,
+            devices: Vec::new(),
             pending_friend_requests: Vec::new(),
             friends_requests: Vec::new(),
             friends: Vec::new(),
-            unread_messages: Vec::new(),
+            totp: None,
+            read_receipts: true,
         }
     }
+
+    pub fn device(&self, device_id: &str) -> Option<&Device> {
+        self.devices
+            .iter()
+            .find(|device| device.device_id == device_id)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Device {
+    pub device_id: String,
+    pub name: Option<String>,
+    pub keys: Key,
+    /// Version of the device's binding in the transparency log.
+    #[serde(default = "default_ik_version")]
+    pub ik_version: i64,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TotpConfig {
+    pub secret: String,
+    pub enabled: bool,
+    /// Argon2 hashes of the unused recovery codes.
+    pub recovery_codes: Vec<String>,
+    pub last_used_step: Option<i64>,
 }
 
 #[derive(Serialize)]
@@ -54,7 +100,7 @@ pub struct UserPublicFriend {
     pub username: String,
     pub description: Option<String>,
     pub profile_picture: Option<String>,
-    pub keys: Key,
+    pub keys: FriendKeys,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -67,30 +113,120 @@ pub struct UserPrivate {
     pub pending_friend_requests: Vec<String>,
     pub friends_requests: Vec<String>,
     pub friends: Vec<String>,
+    pub read_receipts: bool,
+    /// The one-time prekey pool of the session's key set is running low and
+    /// should be replenished.
+    pub opk_low: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageInfo {
     pub uuid: String,
     pub sender: String,
     pub receiver: String,
+    pub sender_device: Option<String>,
+    pub receiver_device: Option<String>,
+    pub created_at: i64,
+    /// Ciphertext length in bytes.
+    pub size: usize,
+    /// Carries the X3DH initial data (`ek_used`) and starts a new session.
+    pub has_prekey_message: bool,
+}
+
+/// Answer of the inbox listing: the whole inbox as a bare array when no
+/// pagination parameter is given, as before pagination existed, a page
+/// otherwise.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum MessageListing {
+    All(Vec<MessageInfo>),
+    Page(MessagePage),
+}
+
+/// One page of the inbox. `next_cursor` is `None` on the last page.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessagePage {
+    pub messages: Vec<MessageInfo>,
+    pub next_cursor: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Key {
     pub ik_pub: [u8; 32],
     pub spk_pub: [u8; 32],
+    /// XEdDSA signature of `spk_pub` by `ik_pub`.
+    #[serde(default)]
+    pub spk_signature: Vec<u8>,
+    #[serde(default)]
+    pub spk_id: String,
+    /// `None` for signed prekeys uploaded before rotation was tracked.
+    #[serde(default)]
+    pub spk_created_at: Option<i64>,
+    /// Rotated-out signed prekeys still accepted until their grace period ends.
+    #[serde(default)]
+    pub previous_spks: Vec<PreviousSignedPreKey>,
     pub opk_pub: Vec<OneTimePreKeyPublic>,
 }
 
 impl Key {
-    pub fn new(ik_pub: [u8; 32], spk_pub: [u8; 32], opk_pub: Vec<OneTimePreKeyPublic>) -> Self {
+    pub fn new(
+        ik_pub: [u8; 32],
+        spk_pub: [u8; 32],
+        spk_signature: Vec<u8>,
+        opk_pub: Vec<OneTimePreKeyPublic>,
+    ) -> Self {
         Self {
             ik_pub,
             spk_pub,
+            spk_signature,
+            spk_id: Uuid::new_v4().to_string(),
+            spk_created_at: Some(chrono::Utc::now().timestamp()),
+            previous_spks: Vec::new(),
             opk_pub,
         }
     }
+
+    /// Whether `spk_id` names the current signed prekey or one still within
+    /// its grace period.
+    pub fn accepts_spk(&self, spk_id: &str, now: i64) -> bool {
+        self.spk_id == spk_id
+            || self
+                .previous_spks
+                .iter()
+                .any(|spk| spk.spk_id == spk_id && spk.expires_at > now)
+    }
+}
+
+/// Long-term keys of a friend, without the one-time prekeys: those are only
+/// handed out one at a time through the prekey bundle.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FriendKeys {
+    pub ik_pub: [u8; 32],
+    pub spk_pub: [u8; 32],
+    pub spk_signature: Vec<u8>,
+    pub spk_id: String,
+    pub spk_created_at: Option<i64>,
+}
+
+impl From<Key> for FriendKeys {
+    fn from(keys: Key) -> Self {
+        Self {
+            ik_pub: keys.ik_pub,
+            spk_pub: keys.spk_pub,
+            spk_signature: keys.spk_signature,
+            spk_id: keys.spk_id,
+            spk_created_at: keys.spk_created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviousSignedPreKey {
+    pub spk_id: String,
+    pub spk_pub: [u8; 32],
+    pub spk_signature: Vec<u8>,
+    pub created_at: Option<i64>,
+    pub expires_at: i64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -5,4 +5,5 @@ pub struct UserUpdatePayload {
     pub username: Option<String>,
     pub description: Option<String>,
     pub profile_picture: Option<String>,
+    pub read_receipts: Option<bool>,
 }
@@ -1,20 +1,35 @@
 use crate::{
+    event::{
+        models::EventKind,
+        services::{delete_events, push_event},
+    },
+    keys::utils::{is_opk_pool_low, KeyTarget},
+    message::models::Message,
+    state::AppState,
     user::{
-        models::{MessageInfo, User, UserPrivate, UserPublic, UserPublicFriend, UserResponse},
+        models::{
+            MessageInfo, MessagePage, User, UserPrivate, UserPublic, UserPublicFriend, UserResponse,
+        },
         payload::UserUpdatePayload,
-        utils::{clean_reference, find_user, update_user_fields},
+        utils::{
+            clean_reference, decode_message_cursor, encode_message_cursor, find_user,
+            update_user_fields,
+        },
     },
     utils::error::error_response,
 };
 use axum::{http::StatusCode, Json};
-use futures::stream::StreamExt;
+use futures::stream::{StreamExt, TryStreamExt};
 use mongodb::bson::Document;
 use mongodb::{bson::doc, Collection};
 use serde_json::{json, Value};
 
+/// The caller's own profile. `opk_low` is about the key set of the calling
+/// session's device.
 pub async fn get_profile(
     users: Collection<User>,
     user_id: &str,
+    device_id: &str,
 ) -> Result<UserPrivate, (StatusCode, Json<Value>)> {
     let user = users
         .find_one(doc! { "uuid": user_id })
@@ -25,6 +40,9 @@ pub async fn get_profile(
             Some("User not found"),
         ))?;
 
+    let opk_low = KeyTarget::for_session(&user, device_id)
+        .keys(&user)
+        .is_some_and(is_opk_pool_low);
     Ok(UserPrivate {
         uuid: user.uuid,
         username: user.username,
@@ -34,6 +52,8 @@ pub async fn get_profile(
         pending_friend_requests: user.pending_friend_requests,
         friends_requests: user.friends_requests,
         friends: user.friends,
+        read_receipts: user.read_receipts,
+        opk_low,
     })
 }
 
@@ -57,7 +77,7 @@ pub async fn get_by_id(
         let user_friend = UserPublicFriend {
             uuid: user.uuid,
             username: user.username,
-            keys: user.keys,
+            keys: user.keys.into(),
             description: user.description,
             profile_picture: user.profile_picture,
         };
@@ -99,6 +119,7 @@ pub async fn get_all(
 pub async fn update_user(
     users: Collection<User>,
     user_id: &str,
+    device_id: &str,
     updates: UserUpdatePayload,
 ) -> Result<UserPrivate, (StatusCode, Json<Value>)> {
     let mut set_doc: Document = Document::new();
@@ -130,6 +151,9 @@ pub async fn update_user(
     if let Some(profile_picture) = &updates.profile_picture {
         set_doc.insert("profile_picture", profile_picture);
     }
+    if let Some(read_receipts) = updates.read_receipts {
+        set_doc.insert("read_receipts", read_receipts);
+    }
 
     if set_doc.is_empty() {
         return Err(error_response(
@@ -145,13 +169,11 @@ pub async fn update_user(
         .await
         .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
 
-    get_profile(users, user_id).await
+    get_profile(users, user_id, device_id).await
 }
 
-pub async fn delete_user(
-    users: Collection<User>,
-    user_id: &str,
-) -> Result<(), (StatusCode, Json<Value>)> {
+pub async fn delete_user(state: &AppState, user_id: &str) -> Result<(), (StatusCode, Json<Value>)> {
+    let users = state.get_user_collection();
     let user = find_user(&users, user_id).await?;
 
     let pending_requests = user.pending_friend_requests.clone();
@@ -169,6 +191,11 @@ pub async fn delete_user(
             Some("User not found"),
         ));
     }
+    let _ = state
+        .get_message_collection()
+        .delete_many(doc! { "receiver": user_id })
+        .await;
+    let _ = delete_events(state, user_id).await;
     clean_reference(&users, friends, "friends", |u| &mut u.friends, user_id).await;
 
     clean_reference(
@@ -193,10 +220,11 @@ pub async fn delete_user(
 }
 
 pub async fn request_friendship(
-    users: Collection<User>,
+    state: &AppState,
     user_id: &str,
     friend_id: &str,
 ) -> Result<Json<serde_json::Value>, (StatusCode, Json<Value>)> {
+    let users = state.get_user_collection();
     let mut user = find_user(&users, user_id).await?;
 
     let mut friend = find_user(&users, friend_id).await?;
@@ -244,7 +272,7 @@ pub async fn request_friendship(
             .pending_friend_requests
             .contains(&user_id.to_string())
     {
-        accept_friendship(users, user_id, friend_id).await?;
+        accept_friendship(state, user_id, friend_id).await?;
         return Ok(Json(
             json!({"message": "Friendship auto-accepted (mutual request)"}),
         ));
@@ -266,14 +294,24 @@ pub async fn request_friendship(
     )
     .await?;
 
+    let _ = push_event(
+        state,
+        &[friend_id.to_string()],
+        EventKind::FriendRequestReceived {
+            user_id: user_id.to_string(),
+        },
+    )
+    .await;
+
     Ok(Json(json!({"message": "Friend request sent!"})))
 }
 
 pub async fn accept_friendship(
-    users: Collection<User>,
+    state: &AppState,
     user_id: &str,
     friend_id: &str,
 ) -> Result<(), (StatusCode, Json<Value>)> {
+    let users = state.get_user_collection();
     let mut user = find_user(&users, user_id).await?;
     let mut friend = find_user(&users, friend_id).await?;
 
@@ -323,6 +361,15 @@ pub async fn accept_friendship(
     .await?;
     update_user_fields(&users, friend_id, doc! { "pending_friend_requests": friend.pending_friend_requests, "friends": friend.friends }).await?;
 
+    let _ = push_event(
+        state,
+        &[friend_id.to_string()],
+        EventKind::FriendRequestAccepted {
+            user_id: user_id.to_string(),
+        },
+    )
+    .await;
+
     Ok(())
 }
 
@@ -413,19 +460,92 @@ pub async fn remove_friendship(
     Ok(())
 }
 
+/// Default and maximum page sizes of the inbox listing.
+pub const DEFAULT_MESSAGE_PAGE_SIZE: i64 = 50;
+pub const MAX_MESSAGE_PAGE_SIZE: i64 = 200;
+
+/// Unread messages meant for the calling device (those addressed to it and
+/// those addressed to the account keys), oldest first, one page at a time.
 pub async fn get_messages(
-    users: Collection<User>,
+    messages: Collection<Message>,
     user_id: &str,
+    device_id: &str,
+    cursor: Option<&str>,
+    sender: Option<&str>,
+    limit: Option<i64>,
+) -> Result<MessagePage, (StatusCode, Json<Value>)> {
+    let limit = limit
+        .unwrap_or(DEFAULT_MESSAGE_PAGE_SIZE)
+        .clamp(1, MAX_MESSAGE_PAGE_SIZE);
+
+    let mut filter = doc! {
+        "receiver": user_id,
+        "receiver_device": { "$in": [device_id, null] },
+    };
+    if let Some(sender) = sender {
+        filter.insert("sender", sender);
+    }
+    if let Some(cursor) = cursor {
+        let (created_at, uuid) = decode_message_cursor(cursor).ok_or(error_response(
+            StatusCode::BAD_REQUEST,
+            Some("Invalid cursor"),
+        ))?;
+        filter.insert(
+            "$or",
+            vec![
+                doc! { "created_at": { "$gt": created_at } },
+                doc! { "created_at": created_at, "uuid": { "$gt": uuid } },
+            ],
+        );
+    }
+
+    // One extra message tells whether another page follows.
+    let mut page: Vec<Message> = messages
+        .find(filter)
+        .sort(doc! { "created_at": 1, "uuid": 1 })
+        .limit(limit + 1)
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?
+        .try_collect()
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, Some("Database error")))?;
+
+    let has_more = page.len() as i64 > limit;
+    page.truncate(limit as usize);
+    let next_cursor = page
+        .last()
+        .filter(|_| has_more)
+        .map(|last| encode_message_cursor(last.created_at, &last.uuid));
+
+    Ok(MessagePage {
+        messages: page.iter().map(Message::message_info).collect(),
+        next_cursor,
+    })
+}
+
+/// Every unread message meant for the calling device, oldest first, for
+/// clients predating pagination.
+pub async fn get_all_messages(
+    messages: Collection<Message>,
+    user_id: &str,
+    device_id: &str,
 ) -> Result<Vec<MessageInfo>, (StatusCode, Json<Value>)> {
-    let user = find_user(&users, user_id).await?;
-    let messages_info = user
-        .unread_messages
-        .iter()
-        .map(|message| MessageInfo {
-            uuid: message.uuid.clone(),
-            sender: message.sender.clone(),
-            receiver: message.receiver.clone(),
-        })
-        .collect();
-    Ok(messages_info)
+    let mut all = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = get_messages(
+            messages.clone(),
+            user_id,
+            device_id,
+            cursor.as_deref(),
+            None,
+            Some(MAX_MESSAGE_PAGE_SIZE),
+        )
+        .await?;
+        all.extend(page.messages);
+        match page.next_cursor {
+            Some(next) => cursor = Some(next),
+            None => return Ok(all),
+        }
+    }
 }
@@ -1,5 +1,6 @@
 use crate::{user::models::User, utils::error::error_response};
 use axum::{http::StatusCode, Json};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use mongodb::{
     bson::{doc, Document},
     Collection,
@@ -56,3 +57,15 @@ pub async fn clean_reference(
     });
     futures::future::join_all(tasks).await;
 }
+
+/// Opaque inbox cursor: base64url of `{created_at}:{uuid}` of the last message
+/// returned.
+pub fn encode_message_cursor(created_at: i64, uuid: &str) -> String {
+    URL_SAFE_NO_PAD.encode(format!("{created_at}:{uuid}"))
+}
+
+pub fn decode_message_cursor(cursor: &str) -> Option<(i64, String)> {
+    let decoded = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+    let (created_at, uuid) = decoded.split_once(':')?;
+    Some((created_at.parse().ok()?, uuid.to_string()))
+}
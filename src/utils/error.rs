@@ -1,4 +1,8 @@
-use axum::{http::StatusCode, Json};
+use axum::{
+    http::{header::RETRY_AFTER, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
 use serde_json::{json, Value};
 
 pub fn error_response(status: StatusCode, message: Option<&str>) -> (StatusCode, Json<Value>) {
@@ -8,6 +12,7 @@ pub fn error_response(status: StatusCode, message: Option<&str>) -> (StatusCode,
         StatusCode::FORBIDDEN => "Forbidden",
         StatusCode::NOT_FOUND => "Resource not found",
         StatusCode::CONFLICT => "Conflict",
+        StatusCode::TOO_MANY_REQUESTS => "Too many requests",
         StatusCode::INTERNAL_SERVER_ERROR => "Internal server error",
         _ => "An error occurred",
     };
@@ -24,3 +29,28 @@ pub fn error_response(status: StatusCode, message: Option<&str>) -> (StatusCode,
         })),
     )
 }
+
+pub fn too_many_requests(retry_after: u64) -> Response {
+    (
+        [(RETRY_AFTER, retry_after.to_string())],
+        error_response(StatusCode::TOO_MANY_REQUESTS, None),
+    )
+        .into_response()
+}
+
+/// Maps a Redis failure of the session store to the response it warrants.
+pub fn session_store_error(_: redis::RedisError) -> (StatusCode, Json<Value>) {
+    error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Some("Session store error"),
+    )
+}
+
+/// Whether a Mongo write failed on a unique index.
+pub fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(e))
+            if e.code == 11000
+    )
+}
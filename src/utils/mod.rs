@@ -1 +1,3 @@
 pub mod error;
+pub mod rate_limit;
+pub mod request;
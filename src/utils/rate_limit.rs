@@ -0,0 +1,195 @@
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    response::Response,
+};
+use redis::Client;
+use tower::{Layer, Service};
+use uuid::Uuid;
+
+use crate::utils::{error::too_many_requests, request::ClientAddr};
+
+const LIMIT_HEADER: HeaderName = HeaderName::from_static("ratelimit-limit");
+const REMAINING_HEADER: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RESET_HEADER: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// How many requests a subject may make to a route group within a sliding
+/// window.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitPolicy {
+    pub name: &'static str,
+    pub limit: u64,
+    pub window_secs: u64,
+}
+
+impl RateLimitPolicy {
+    pub const fn new(name: &'static str, limit: u64, window_secs: u64) -> Self {
+        Self {
+            name,
+            limit,
+            window_secs,
+        }
+    }
+}
+
+pub const AUTH: RateLimitPolicy = RateLimitPolicy::new("auth", 30, 60);
+pub const REGISTER: RateLimitPolicy = RateLimitPolicy::new("register", 5, 60 * 60);
+pub const USER: RateLimitPolicy = RateLimitPolicy::new("user", 120, 60);
+pub const FRIEND_REQUESTS: RateLimitPolicy = RateLimitPolicy::new("friends", 20, 60);
+pub const KEYS: RateLimitPolicy = RateLimitPolicy::new("keys", 120, 60);
+pub const MESSAGE: RateLimitPolicy = RateLimitPolicy::new("message", 300, 60);
+pub const MESSAGE_SEND: RateLimitPolicy = RateLimitPolicy::new("message_send", 60, 60);
+
+struct Decision {
+    allowed: bool,
+    remaining: u64,
+    reset_secs: u64,
+}
+
+/// Sliding window log: one sorted-set member per accepted request, scored by
+/// its timestamp in milliseconds.
+async fn check(
+    redis: &Client,
+    policy: &RateLimitPolicy,
+    subject: &str,
+) -> redis::RedisResult<Decision> {
+    let mut conn = redis.clone().get_multiplexed_async_connection().await?;
+    let script = redis::Script::new(
+        r"
+        local now = tonumber(ARGV[1])
+        local window = tonumber(ARGV[2])
+        local limit = tonumber(ARGV[3])
+        redis.call('ZREMRANGEBYSCORE', KEYS[1], 0, now - window)
+        local count = redis.call('ZCARD', KEYS[1])
+        local allowed = 0
+        if count < limit then
+            redis.call('ZADD', KEYS[1], now, ARGV[4])
+            count = count + 1
+            allowed = 1
+        end
+        redis.call('PEXPIRE', KEYS[1], window)
+        local reset = window
+        local oldest = redis.call('ZRANGE', KEYS[1], 0, 0, 'WITHSCORES')
+        if oldest[2] then
+            reset = tonumber(oldest[2]) + window - now
+        end
+        return {allowed, limit - count, reset}
+        ",
+    );
+    let (allowed, remaining, reset_ms): (i64, i64, i64) = script
+        .key(format!("rate_limit:{}:{subject}", policy.name))
+        .arg(chrono::Utc::now().timestamp_millis())
+        .arg(policy.window_secs * 1000)
+        .arg(policy.limit)
+        .arg(Uuid::new_v4().to_string())
+        .invoke_async(&mut conn)
+        .await?;
+
+    Ok(Decision {
+        allowed: allowed == 1,
+        remaining: remaining.max(0) as u64,
+        reset_secs: (reset_ms.max(0) as u64).div_ceil(1000),
+    })
+}
+
+/// Requests are counted per authenticated user when `require_access_token`
+/// already ran, per trusted client address otherwise. Requests with neither,
+/// e.g. ones that did not come through the proxy, share one bucket.
+fn subject(req: &Request) -> String {
+    if let Some(user_id) = req.extensions().get::<String>() {
+        return format!("user:{user_id}");
+    }
+    req.extensions().get::<ClientAddr>().map_or_else(
+        || "anonymous".to_string(),
+        |ClientAddr(ip)| format!("ip:{ip}"),
+    )
+}
+
+fn set_headers(response: &mut Response, policy: &RateLimitPolicy, decision: &Decision) {
+    let headers = response.headers_mut();
+    headers.insert(LIMIT_HEADER, HeaderValue::from(policy.limit));
+    headers.insert(REMAINING_HEADER, HeaderValue::from(decision.remaining));
+    headers.insert(RESET_HEADER, HeaderValue::from(decision.reset_secs));
+}
+
+/// Tower layer enforcing a [`RateLimitPolicy`] with state kept in Redis, so
+/// the budget is shared by every API instance.
+///
+/// Apply it inside `require_access_token` (i.e. before it in `route_layer`
+/// order) to count per user. Requests go through untouched if Redis is
+/// unavailable.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    redis: Client,
+    policy: RateLimitPolicy,
+}
+
+impl RateLimitLayer {
+    pub fn new(redis: Client, policy: RateLimitPolicy) -> Self {
+        Self { redis, policy }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            redis: self.redis.clone(),
+            policy: self.policy,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    redis: Client,
+    policy: RateLimitPolicy,
+}
+
+impl<S> Service<Request> for RateLimit<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // Take the service that was driven to readiness and leave a fresh clone behind.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let redis = self.redis.clone();
+        let policy = self.policy;
+
+        Box::pin(async move {
+            let Ok(decision) = check(&redis, &policy, &subject(&req)).await else {
+                return inner.call(req).await;
+            };
+
+            if !decision.allowed {
+                let mut response = too_many_requests(decision.reset_secs);
+                set_headers(&mut response, &policy, &decision);
+                return Ok(response);
+            }
+
+            let mut response = inner.call(req).await?;
+            set_headers(&mut response, &policy, &decision);
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,187 @@
+use std::net::IpAddr;
+
+use anyhow::Context;
+use axum::{
+    extract::{Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use shuttle_runtime::SecretStore;
+
+use crate::state::AppState;
+
+pub const MAX_USER_AGENT_LEN: usize = 256;
+pub const MAX_DEVICE_NAME_LEN: usize = 64;
+
+/// Address the client claims in `X-Forwarded-For` (first hop), then
+/// `X-Real-IP`. Client supplied, so only fit for display: security decisions
+/// must use [`ClientAddr`].
+pub fn forwarded_client_ip(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("X-Forwarded-For")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.split(',').next())
+        .or_else(|| headers.get("X-Real-IP").and_then(|v| v.to_str().ok()))
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+pub fn user_agent(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("User-Agent")
+        .and_then(|v| v.to_str().ok())
+        .map(|s| truncate(s, MAX_USER_AGENT_LEN))
+}
+
+/// Keeps at most `max_chars` characters of a client supplied label.
+pub fn truncate(value: &str, max_chars: usize) -> String {
+    value.chars().take(max_chars).collect()
+}
+
+/// Access token of a streaming request: the `Authorization` bearer token, or
+/// the `token` query parameter since browsers cannot set headers on
+/// WebSocket handshakes or `EventSource` requests.
+pub fn stream_token(headers: &HeaderMap, query_token: Option<String>) -> Option<String> {
+    headers
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .map(str::to_string)
+        .or(query_token)
+}
+
+/// How many reverse proxies we run in front of the API, each appending the
+/// address it received the request from to `X-Forwarded-For`.
+#[derive(Debug, Clone, Copy)]
+pub struct ProxyPolicy {
+    pub trusted_hops: usize,
+}
+
+impl Default for ProxyPolicy {
+    /// The platform proxy in front of the service.
+    fn default() -> Self {
+        Self { trusted_hops: 1 }
+    }
+}
+
+impl ProxyPolicy {
+    pub fn from_secret_store(secret_store: &SecretStore) -> anyhow::Result<Self> {
+        let trusted_hops = match secret_store
+            .get("TRUSTED_PROXY_HOPS")
+            .filter(|v| !v.trim().is_empty())
+        {
+            Some(v) => v.trim().parse().context("invalid TRUSTED_PROXY_HOPS")?,
+            None => Self::default().trusted_hops,
+        };
+        Ok(Self { trusted_hops })
+    }
+}
+
+/// Client address as seen by our outermost trusted proxy: the
+/// `X-Forwarded-For` entry it appended, counting from the right. Entries
+/// further left are client supplied and ignored. `None` without trusted
+/// proxies: the runtime does not expose the connection peer address.
+pub fn trusted_client_ip(headers: &HeaderMap, policy: ProxyPolicy) -> Option<IpAddr> {
+    if policy.trusted_hops == 0 {
+        return None;
+    }
+    headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|s| s.split(','))
+        .rev()
+        .nth(policy.trusted_hops - 1)
+        .and_then(|s| s.trim().parse().ok())
+}
+
+/// Address of the client that can be relied upon for rate limiting and
+/// lockouts, set by [`resolve_client_addr`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientAddr(pub IpAddr);
+
+/// Middleware inserting the [`ClientAddr`] extension when the address can be
+/// resolved from the trusted proxies.
+pub async fn resolve_client_addr(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if let Some(ip) = trusted_client_ip(req.headers(), state.proxy_policy) {
+        req.extensions_mut().insert(ClientAddr(ip));
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn forwarded(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("X-Forwarded-For", HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn hops(trusted_hops: usize) -> ProxyPolicy {
+        ProxyPolicy { trusted_hops }
+    }
+
+    #[test]
+    fn reads_the_entry_appended_by_the_outermost_trusted_proxy() {
+        let headers = forwarded(&["203.0.113.7, 10.0.0.2"]);
+        assert_eq!(
+            trusted_client_ip(&headers, hops(1)),
+            Some("10.0.0.2".parse().unwrap())
+        );
+        assert_eq!(
+            trusted_client_ip(&headers, hops(2)),
+            Some("203.0.113.7".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn counts_hops_across_repeated_headers() {
+        let headers = forwarded(&["198.51.100.1", "203.0.113.7, 10.0.0.2", "10.0.0.3"]);
+        assert_eq!(
+            trusted_client_ip(&headers, hops(2)),
+            Some("10.0.0.2".parse().unwrap())
+        );
+        assert_eq!(
+            trusted_client_ip(&headers, hops(4)),
+            Some("198.51.100.1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn ignores_spoofed_entries_on_the_left() {
+        let headers = forwarded(&["1.2.3.4, 5.6.7.8, 203.0.113.7"]);
+        assert_eq!(
+            trusted_client_ip(&headers, hops(1)),
+            Some("203.0.113.7".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn refuses_too_few_hops_or_garbage() {
+        assert_eq!(
+            trusted_client_ip(&forwarded(&["203.0.113.7"]), hops(2)),
+            None
+        );
+        assert_eq!(trusted_client_ip(&HeaderMap::new(), hops(1)), None);
+        assert_eq!(trusted_client_ip(&forwarded(&["not-an-ip"]), hops(1)), None);
+    }
+
+    #[test]
+    fn zero_hops_trusts_no_header() {
+        assert_eq!(
+            trusted_client_ip(&forwarded(&["203.0.113.7"]), hops(0)),
+            None
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Delivery through `Hub` across API instances. The Redis backed tests need a
+//! server at `REDIS_URL` (default `redis://127.0.0.1:6379`):
+//!
+//! ```sh
+//! cargo test --test fanout -- --include-ignored
+//! ```
+
+use std::time::Duration;
+
+use lucchat_api::{
+    message::models::Message,
+    realtime::hub::{Hub, Push, CONNECTION_BUFFER},
+};
+use redis::AsyncCommands;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+const SILENCE: Duration = Duration::from_millis(500);
+
+fn redis() -> redis::Client {
+    let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    redis::Client::open(url).expect("invalid REDIS_URL")
+}
+
+fn message(receiver: &str, receiver_device: Option<&str>) -> Message {
+    Message {
+        uuid: Uuid::new_v4().to_string(),
+        sender: Uuid::new_v4().to_string(),
+        receiver: receiver.to_string(),
+        sender_device: None,
+        receiver_device: receiver_device.map(str::to_string),
+        nonce: [0; 12],
+        ciphertext: vec![1, 2, 3],
+        ratchet_pub: [0; 32],
+        message_index: 0,
+        opk_used: None,
+        ek_used: None,
+        spk_id: None,
+        created_at: chrono::Utc::now().timestamp(),
+    }
+}
+
+fn received_uuid(push: Push) -> String {
+    match push {
+        Push::Message(message) => message.uuid,
+        Push::Event(event) => event.uuid,
+    }
+}
+
+#[tokio::test]
+async fn local_hub_delivers_to_matching_devices() {
+    let hub = Hub::default();
+    let user_id = Uuid::new_v4().to_string();
+    let mut phone = hub.subscribe(&user_id, "phone").await;
+    let mut laptop = hub.subscribe(&user_id, "laptop").await;
+
+    let sent = message(&user_id, Some("laptop"));
+    hub.push_message(&sent).await;
+
+    let received = timeout(RECEIVE_TIMEOUT, laptop.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received_uuid(received), sent.uuid);
+    assert!(timeout(SILENCE, phone.recv()).await.is_err());
+}
+
+#[tokio::test]
+async fn lagging_connection_is_dropped() {
+    let hub = Hub::default();
+    let user_id = Uuid::new_v4().to_string();
+    let mut stalled = hub.subscribe(&user_id, "phone").await;
+    let mut reading = hub.subscribe(&user_id, "laptop").await;
+
+    for _ in 0..=CONNECTION_BUFFER {
+        hub.push_message(&message(&user_id, None)).await;
+        timeout(RECEIVE_TIMEOUT, reading.recv())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    for _ in 0..CONNECTION_BUFFER {
+        assert!(stalled.recv().await.is_some());
+    }
+    assert!(timeout(RECEIVE_TIMEOUT, stalled.recv())
+        .await
+        .unwrap()
+        .is_none());
+
+    let sent = message(&user_id, None);
+    hub.push_message(&sent).await;
+    let received = timeout(RECEIVE_TIMEOUT, reading.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received_uuid(received), sent.uuid);
+}
+
+#[tokio::test]
+#[ignore = "requires a local Redis"]
+async fn push_reaches_a_connection_on_another_instance() {
+    let sender_node = Hub::with_redis(redis());
+    let receiver_node = Hub::with_redis(redis());
+    let user_id = Uuid::new_v4().to_string();
+    let mut subscription = receiver_node.subscribe(&user_id, "phone").await;
+
+    let sent = message(&user_id, None);
+    sender_node.push_message(&sent).await;
+
+    let received = timeout(RECEIVE_TIMEOUT, subscription.recv())
+        .await
+        .expect("no push within the timeout")
+        .unwrap();
+    assert_eq!(received_uuid(received), sent.uuid);
+}
+
+#[tokio::test]
+#[ignore = "requires a local Redis"]
+async fn device_addressed_push_only_reaches_that_device() {
+    let sender_node = Hub::with_redis(redis());
+    let receiver_node = Hub::with_redis(redis());
+    let user_id = Uuid::new_v4().to_string();
+    let mut phone = receiver_node.subscribe(&user_id, "phone").await;
+    let mut laptop = receiver_node.subscribe(&user_id, "laptop").await;
+
+    let sent = message(&user_id, Some("phone"));
+    sender_node.push_message(&sent).await;
+
+    let received = timeout(RECEIVE_TIMEOUT, phone.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received_uuid(received), sent.uuid);
+    assert!(timeout(SILENCE, laptop.recv()).await.is_err());
+}
+
+#[tokio::test]
+#[ignore = "requires a local Redis"]
+async fn push_is_delivered_once_on_the_sending_instance() {
+    let node = Hub::with_redis(redis());
+    let user_id = Uuid::new_v4().to_string();
+    let mut subscription = node.subscribe(&user_id, "phone").await;
+
+    let sent = message(&user_id, None);
+    node.push_message(&sent).await;
+
+    let received = timeout(RECEIVE_TIMEOUT, subscription.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received_uuid(received), sent.uuid);
+    assert!(timeout(SILENCE, subscription.recv()).await.is_err());
+}
+
+#[tokio::test]
+#[ignore = "requires a local Redis"]
+async fn last_connection_closing_unsubscribes_the_channel() {
+    let node = Hub::with_redis(redis());
+    let user_id = Uuid::new_v4().to_string();
+    let channel = format!("inbox:{user_id}");
+    let mut conn = redis().get_multiplexed_async_connection().await.unwrap();
+
+    let subscription = node.subscribe(&user_id, "phone").await;
+    let subscribers: i64 = conn.publish(&channel, "ping").await.unwrap();
+    assert_eq!(subscribers, 1);
+
+    drop(subscription);
+    let unsubscribed = timeout(RECEIVE_TIMEOUT, async {
+        loop {
+            let subscribers: i64 = conn.publish(&channel, "ping").await.unwrap();
+            if subscribers == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    assert!(unsubscribed.is_ok());
+}